@@ -1,31 +1,33 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script copies the memory layout of each binary from `memory/` into a directory
+//! of its own as `memory.x`, and puts only that directory on the linker search path of the
+//! binary. The firmware and the bootloader share the flash, so they need different layouts.
+//! By requesting that Cargo re-run the build script whenever `memory/` is changed, updating a
+//! layout ensures a rebuild with the new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
-    // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
-    println!("cargo:rustc-link-search={}", out.display());
+    let layouts: [(&str, &[u8]); 2] = [
+        ("rp2040-project-template", include_bytes!("memory/app.x")),
+        ("bootloader", include_bytes!("memory/bootloader.x")),
+    ];
+    for (bin, layout) in layouts {
+        let dir = out.join(bin);
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("memory.x"))
+            .unwrap()
+            .write_all(layout)
+            .unwrap();
+        println!("cargo:rustc-link-arg-bin={}=-L{}", bin, dir.display());
+    }
 
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
+    // any file in the project changes. By specifying `memory/`
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // a layout is changed.
+    println!("cargo:rerun-if-changed=memory");
 }
@@ -0,0 +1,6 @@
+MEMORY {
+    /* The bootloader with boot2 takes the first 32K of the flash, see bootloader.x. The
+       image ends where the staging area for firmware updates starts, see boot_state.rs. */
+    FLASH : ORIGIN = 0x10008000, LENGTH = 0xf8000
+    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+}
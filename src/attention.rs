@@ -0,0 +1,112 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use cortex_m::interrupt::Mutex;
+use rp2040_hal::pac::{self, interrupt};
+
+use crate::registry::SlotMask;
+
+/// EDGE_LOW bit in the four interrupt bits each GPIO has in the INTR and INTS registers.
+const EDGE_LOW: u32 = 1 << 2;
+
+/// GPIOs whose data ready line went low since the scan task last looked.
+static SIGNALLED: AtomicU32 = AtomicU32::new(0);
+static ATTENTION_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+/// Data ready lines from the modules. A module pulls its line low while it has input waiting,
+/// so it can be read right away instead of waiting for its turn in the scan. A line is either
+/// shared by several slots or belongs to a single one.
+///
+/// Only slots enabled by the host are read on demand, all others stay in the regular scan.
+pub(crate) struct Attention {
+    /// GPIO of each line and the slots sharing it.
+    lines: Vec<(u8, SlotMask)>,
+    enabled: SlotMask,
+}
+
+impl Attention {
+    pub(crate) fn new() -> Self {
+        unsafe {
+            pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0);
+        }
+        Self {
+            lines: Vec::new(),
+            enabled: 0,
+        }
+    }
+
+    /// Watches `gpio` for the slots in `slots`. The pin has to be configured as an input with
+    /// pull-up and its EdgeLow interrupt enabled.
+    pub(crate) fn add_line(&mut self, gpio: u8, slots: SlotMask) {
+        self.lines.push((gpio, slots));
+    }
+
+    pub(crate) fn set_enabled(&mut self, slot: usize, enabled: bool) {
+        if enabled {
+            self.enabled |= 1 << slot;
+        } else {
+            self.enabled &= !(1 << slot);
+        }
+    }
+
+    /// Slots that are read when their line is asserted.
+    pub(crate) fn on_demand(&self) -> SlotMask {
+        self.lines.iter().fold(0, |slots, (_, line)| slots | line) & self.enabled
+    }
+
+    /// Ready with the on-demand slots whose line went low since the last call.
+    pub(crate) fn poll_signalled(&self, cx: &mut Context<'_>) -> Poll<SlotMask> {
+        let pins = cortex_m::interrupt::free(|cs| {
+            ATTENTION_WAKER.borrow(cs).replace(Some(cx.waker().clone()));
+            let pins = SIGNALLED.load(Ordering::Acquire);
+            SIGNALLED.store(0, Ordering::Release);
+            pins
+        });
+        match self.slots_on(pins) {
+            0 => Poll::Pending,
+            slots => Poll::Ready(slots),
+        }
+    }
+
+    /// On-demand slots whose line is low right now.
+    pub(crate) fn asserted(&self) -> SlotMask {
+        let sio = unsafe { &*pac::SIO::ptr() };
+        self.slots_on(!sio.gpio_in.read().bits())
+    }
+
+    fn slots_on(&self, pins: u32) -> SlotMask {
+        self.lines
+            .iter()
+            .filter(|(gpio, _)| pins & (1 << gpio) != 0)
+            .fold(0, |slots, (_, line)| slots | line)
+            & self.enabled
+    }
+}
+
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    let mut pins = 0u32;
+    // Each register holds the interrupt bits of eight GPIOs
+    for reg in 0..4 {
+        let status = io.proc0_ints[reg].read().bits();
+        io.intr[reg].write(|w| unsafe { w.bits(status) });
+        for i in 0..8 {
+            if status & (EDGE_LOW << (4 * i)) != 0 {
+                pins |= 1 << (reg * 8 + i);
+            }
+        }
+    }
+    cortex_m::interrupt::free(|cs| {
+        SIGNALLED.store(SIGNALLED.load(Ordering::Relaxed) | pins, Ordering::Release);
+        if let Some(waker) = ATTENTION_WAKER.borrow(cs).take() {
+            waker.wake();
+        }
+    });
+}
@@ -0,0 +1,98 @@
+//! Swaps in new firmware images and rolls back the ones that do not confirm their first boot,
+//! then starts the image at `ACTIVE_OFFSET`. It is never written by a firmware update, so it
+//! can pick up a swap that was cut short by a reset or a power loss.
+#![no_std]
+#![no_main]
+
+use defmt::{info, warn};
+use defmt_rtt as _;
+use embedded_hal::watchdog::{WatchdogDisable, WatchdogEnable};
+use fugit::ExtU32;
+use panic_probe as _;
+
+use rp2040_hal::{entry, pac, rom_data, watchdog::Watchdog};
+
+use rp2040_project_template::{
+    boot_state::{BootState, Record, ACTIVE_OFFSET, SCRATCH_OFFSET, STAGING_OFFSET},
+    flash::{self, FLASH_BASE, SECTOR_SIZE},
+};
+
+/// Time a new image gets to start feeding the watchdog, in microseconds. About the longest
+/// the watchdog allows.
+const TRIAL_TIMEOUT_US: u32 = 8_000_000;
+
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+
+#[entry]
+fn main() -> ! {
+    let pac = pac::Peripherals::take().unwrap();
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+    // A watchdog left running by the image would cut a swap short
+    watchdog.disable();
+    let mut record = Record::load();
+    match record.state {
+        BootState::SwapPending => {
+            swap(&record);
+            record.store(BootState::TrialBooted, record.len);
+        }
+        BootState::TrialBooted => {
+            warn!("New firmware did not confirm its first boot, rolling back");
+            record.store(BootState::RollbackPending, record.len);
+            // The previous image is still in the staging area
+            swap(&record);
+            record.store(BootState::Idle, record.len);
+        }
+        BootState::RollbackPending => {
+            swap(&record);
+            record.store(BootState::Idle, record.len);
+        }
+        BootState::Idle => {}
+    }
+    if record.state == BootState::TrialBooted {
+        // The image has not set up the crystal yet, so the ticks run off the ring oscillator
+        // and the timeout is only roughly right until it re-arms the watchdog
+        watchdog.enable_tick_generation(12);
+        watchdog.pause_on_debug(true);
+        watchdog.start(TRIAL_TIMEOUT_US.micros());
+    }
+    start_image()
+}
+
+/// Exchanges the image at `ACTIVE_OFFSET` with the one at `STAGING_OFFSET`. Each sector takes
+/// three steps through the scratch sector and each step is logged once it is done, so after
+/// a reset the swap carries on with the first step that is not. Every step can be repeated, as
+/// it only overwrites what a later step reads from.
+fn swap(record: &Record) {
+    let sectors = (record.len as usize).div_ceil(SECTOR_SIZE);
+    let done = record.progress();
+    info!("Swapping {} firmware sectors, {} steps done", sectors, done);
+    let mut buffer = [0u8; SECTOR_SIZE];
+    for step in done..sectors * 3 {
+        let offset = (step / 3 * SECTOR_SIZE) as u32;
+        let (from, to) = match step % 3 {
+            0 => (ACTIVE_OFFSET + offset, SCRATCH_OFFSET),
+            1 => (STAGING_OFFSET + offset, ACTIVE_OFFSET + offset),
+            _ => (SCRATCH_OFFSET, STAGING_OFFSET + offset),
+        };
+        buffer.copy_from_slice(flash::read(from, SECTOR_SIZE));
+        flash::write(to, &buffer);
+        record.log_step(step);
+    }
+}
+
+/// Jumps to the image at `ACTIVE_OFFSET`, or to the USB bootloader if there is none.
+fn start_image() -> ! {
+    let vector_table = (FLASH_BASE + ACTIVE_OFFSET) as *const u32;
+    // The initial stack pointer of an image points into RAM, erased flash does not
+    let stack = unsafe { vector_table.read_volatile() };
+    if stack & 0xfff0_0000 != 0x2000_0000 {
+        warn!("No firmware to start");
+        rom_data::reset_to_usb_boot(0, 0);
+    }
+    unsafe {
+        (*pac::SCB::PTR).vtor.write(vector_table as u32);
+        cortex_m::asm::bootload(vector_table)
+    }
+}
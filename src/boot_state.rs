@@ -0,0 +1,124 @@
+use crate::flash::{self, PAGE_SIZE, SECTOR_SIZE};
+
+/// The bootloader takes the first 32K of the flash and the running image follows it. New
+/// images are written to the staging area first and swapped in by the bootloader, sector by
+/// sector through the scratch sector. See memory/app.x.
+pub const ACTIVE_OFFSET: u32 = 0x8000;
+pub const STAGING_OFFSET: u32 = 0x10_0000;
+pub const MAX_IMAGE_BYTES: u32 = 0xf_8000;
+pub const SCRATCH_OFFSET: u32 = 0x1f_d000;
+/// The newest `Record` alternates between these sectors, so the previous one stays intact
+/// while the next one is written.
+const STATE_OFFSETS: [u32; 2] = [0x1f_e000, 0x1f_f000];
+
+const STATE_MAGIC: u32 = 0x4e45_4749;
+/// The steps of a swap are logged from here on, one word each.
+const LOG_OFFSET: usize = PAGE_SIZE;
+
+/// What the next boot has to do.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BootState {
+    Idle = 0,
+    /// A verified image is waiting in the staging area.
+    SwapPending = 1,
+    /// The new image booted but has not confirmed it works yet.
+    TrialBooted = 2,
+    /// The new image did not confirm, the previous one is being swapped back in.
+    RollbackPending = 3,
+}
+
+/// The boot state as kept in one of the state sectors, followed by the log of the swap it
+/// asks for.
+pub struct Record {
+    pub state: BootState,
+    /// Length of the staged image in bytes.
+    pub len: u32,
+    seq: u32,
+    offset: u32,
+}
+
+impl Record {
+    /// Reads the newest intact record, or an idle one if there is none.
+    pub fn load() -> Self {
+        STATE_OFFSETS
+            .iter()
+            .filter_map(|offset| Self::read(*offset))
+            .max_by_key(|record| record.seq)
+            .unwrap_or(Self {
+                state: BootState::Idle,
+                len: 0,
+                seq: 0,
+                offset: STATE_OFFSETS[1],
+            })
+    }
+
+    fn read(offset: u32) -> Option<Self> {
+        let words = flash::read(offset, 16);
+        let word = |i: usize| u32::from_le_bytes(words[i * 4..i * 4 + 4].try_into().unwrap());
+        let (state, len, seq) = (word(0), word(1), word(2));
+        // Also catches a record that was cut short while it was written
+        if word(3) != STATE_MAGIC ^ state ^ len ^ seq || len > MAX_IMAGE_BYTES {
+            return None;
+        }
+        let state = match state {
+            0 => BootState::Idle,
+            1 => BootState::SwapPending,
+            2 => BootState::TrialBooted,
+            3 => BootState::RollbackPending,
+            _ => return None,
+        };
+        Some(Self {
+            state,
+            len,
+            seq,
+            offset,
+        })
+    }
+
+    /// Replaces the record with a new one with an empty log. It goes to the other state
+    /// sector, so a reset while it is written leaves the current one in effect.
+    pub fn store(&mut self, state: BootState, len: u32) {
+        let seq = self.seq.wrapping_add(1);
+        let offset = if self.offset == STATE_OFFSETS[0] {
+            STATE_OFFSETS[1]
+        } else {
+            STATE_OFFSETS[0]
+        };
+        let words = [
+            state as u32,
+            len,
+            seq,
+            STATE_MAGIC ^ state as u32 ^ len ^ seq,
+        ];
+        let mut sector = [0xff; SECTOR_SIZE];
+        for (i, word) in words.iter().enumerate() {
+            sector[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        flash::write(offset, &sector);
+        *self = Self {
+            state,
+            len,
+            seq,
+            offset,
+        };
+    }
+
+    /// Number of swap steps logged as done. A word that was only partly programmed does not
+    /// count, so its step is done again.
+    pub fn progress(&self) -> usize {
+        flash::read(self.offset + LOG_OFFSET as u32, SECTOR_SIZE - LOG_OFFSET)
+            .chunks(4)
+            .take_while(|word| word.iter().all(|byte| *byte == 0))
+            .count()
+    }
+
+    /// Logs swap step `step` as done by clearing its word. The rest of the page is programmed
+    /// with ones, which leaves it as it is.
+    pub fn log_step(&self, step: usize) {
+        let at = LOG_OFFSET + step * 4;
+        let page = at / PAGE_SIZE * PAGE_SIZE;
+        let mut data = [0xff; PAGE_SIZE];
+        data[at - page..at - page + 4].fill(0);
+        flash::program(self.offset + page as u32, &data);
+    }
+}
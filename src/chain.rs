@@ -0,0 +1,267 @@
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::cell::Cell;
+
+use defmt::{debug, warn};
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use rp2040_hal::pac;
+use ux::u7;
+
+use crate::{
+    command,
+    registry::{SlotMask, MAX_SLOTS},
+    spi_downstream::DownstreamDevice,
+    time,
+};
+
+/// Id of the controller connected to the host.
+pub(crate) const ROOT_ID: u8 = 1;
+/// Id modules leave in their events. Replaced by the id of the controller they are attached
+/// to, and never given to a controller.
+pub(crate) const MODULE_ID: u8 = 0;
+/// Id of a chained controller that has not been given one by the root yet.
+pub(crate) const UNASSIGNED: u8 = 0xfe;
+
+/// The id of this controller, shared by all tasks. Chained controllers start without one and
+/// ask the root for it, identifying themselves by a random nonce until they have it.
+pub(crate) struct Identity {
+    id: Cell<u8>,
+    nonce: Cell<u16>,
+}
+
+impl Identity {
+    pub(crate) fn new() -> Self {
+        Self {
+            id: Cell::new(UNASSIGNED),
+            nonce: Cell::new(random_nonce()),
+        }
+    }
+
+    pub(crate) fn get(&self) -> u8 {
+        self.id.get()
+    }
+
+    pub(crate) fn is_assigned(&self) -> bool {
+        self.id.get() != UNASSIGNED
+    }
+
+    pub(crate) fn nonce(&self) -> u16 {
+        self.nonce.get()
+    }
+
+    pub(crate) fn claim_root(&self) {
+        self.id.set(ROOT_ID);
+    }
+
+    /// Takes `id` if the assignment is meant for this controller. Returns true if it was.
+    pub(crate) fn assign(&self, nonce: u16, id: u8) -> bool {
+        if self.is_assigned() || nonce != self.nonce.get() {
+            return false;
+        }
+        self.id.set(id);
+        true
+    }
+
+    /// Gives up the id, so a new one is requested with a fresh nonce.
+    pub(crate) fn release(&self) {
+        self.id.set(UNASSIGNED);
+        self.nonce.set(random_nonce());
+    }
+}
+
+/// Collects a nonce from the random bit of the ring oscillator.
+fn random_nonce() -> u16 {
+    let rosc = unsafe { &*pac::ROSC::ptr() };
+    (0..16).fold(0u16, |nonce, _| {
+        nonce << 1 | rosc.randombit.read().randombit().bit() as u16
+    })
+}
+
+/// Returns true if `id` can belong to a chained controller.
+fn is_chained_id(id: u8) -> bool {
+    id != MODULE_ID && id != ROOT_ID && id != UNASSIGNED && id != command::ANY_CONTROLLER
+}
+
+/// Hands out the ids of chained controllers on the root. Ids are never reused while the root
+/// runs, and ids seen in events are skipped, so controllers that kept theirs across a reset of
+/// the root are not collided with.
+pub(crate) struct IdAllocator {
+    given: BTreeMap<u16, u8>,
+    used: BTreeSet<u8>,
+}
+
+impl IdAllocator {
+    pub(crate) fn new() -> Self {
+        Self {
+            given: BTreeMap::new(),
+            used: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn note(&mut self, id: u8) {
+        if is_chained_id(id) {
+            self.used.insert(id);
+        }
+    }
+
+    /// Returns the id for the controller that sent `nonce`. A repeated request gets the same id,
+    /// as the assignment may have been lost on the way.
+    pub(crate) fn allocate(&mut self, nonce: u16) -> Option<u8> {
+        if let Some(id) = self.given.get(&nonce) {
+            return Some(*id);
+        }
+        let id = (ROOT_ID + 1..UNASSIGNED).find(|id| !self.used.contains(id))?;
+        self.given.insert(nonce, id);
+        self.used.insert(id);
+        Some(id)
+    }
+}
+
+/// Chain bookkeeping of the scan task for the events coming up from the slots.
+pub(crate) struct Chain<'a> {
+    identity: &'a Identity,
+    allocator: IdAllocator,
+    /// Slots that sent our own id back, already reported.
+    looped: SlotMask,
+}
+
+impl<'a> Chain<'a> {
+    pub(crate) fn new(identity: &'a Identity) -> Self {
+        Self {
+            identity,
+            allocator: IdAllocator::new(),
+            looped: 0,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        self.identity.get()
+    }
+
+    /// Stamps an event that came up from `ds` in `slot` with our id if a module sent it.
+    /// Returns what to send upstream, or None if the event was taken care of here.
+    pub(crate) fn inbound(
+        &mut self,
+        slot: usize,
+        ds: &mut DownstreamDevice,
+        mut event: NegiconEvent,
+    ) -> Option<NegiconEvent> {
+        let id = self.identity.get();
+        if self.identity.is_assigned() && event.controller_id == id {
+            // Our own events made it around a loop in the cabling, so they stop here
+            if self.looped & (1 << slot) != 0 {
+                return None;
+            }
+            warn!("Slot {} is chained back to this controller", slot);
+            self.looped |= 1 << slot;
+            return Some(command::report(command::CHAIN_LOOP, id, slot as u8, 0));
+        }
+        if id == ROOT_ID {
+            if event.id == command::ID_REQUEST {
+                self.assign(slot, ds, event.value as u16);
+                return None;
+            }
+            self.allocator.note(event.controller_id);
+        }
+        if event.controller_id == MODULE_ID {
+            event.controller_id = id;
+        }
+        Some(event)
+    }
+
+    fn assign(&mut self, slot: usize, ds: &mut DownstreamDevice, nonce: u16) {
+        let id = match self.allocator.allocate(nonce) {
+            Some(id) => id,
+            None => {
+                warn!("No controller id left to assign");
+                return;
+            }
+        };
+        debug!("Assigning id {} to controller behind slot {}", id, slot);
+        let assignment = NegiconEvent::new(
+            NegiconEventType::Output,
+            command::ASSIGN_ID,
+            u7::new(0),
+            nonce as i16,
+            UNASSIGNED,
+            id,
+        );
+        if let Err(e) = ds.forward(assignment) {
+            // The controller asks again with its next heartbeat
+            warn!("Could not pass controller id on: {:?}", e);
+        }
+    }
+}
+
+/// Returns an id that more than one slot reaches, which means two controllers use it.
+pub(crate) fn find_duplicate(downstreams: &[DownstreamDevice]) -> Option<u8> {
+    downstreams.iter().enumerate().find_map(|(slot, ds)| {
+        ds.chained().filter(|id| is_chained_id(*id)).find(|id| {
+            downstreams[slot + 1..]
+                .iter()
+                .any(|other| other.reaches(*id))
+        })
+    })
+}
+
+/// Sends a probe to each controller chained directly behind a slot and times its answer.
+pub(crate) struct HopProbe {
+    sent_us: u32,
+    probed: SlotMask,
+    pending: SlotMask,
+}
+
+impl HopProbe {
+    pub(crate) fn start(downstreams: &mut [DownstreamDevice]) -> Self {
+        let mut probed: SlotMask = 0;
+        for (slot, ds) in downstreams.iter_mut().enumerate() {
+            if !ds.is_present() || !ds.chained().any(is_chained_id) {
+                continue;
+            }
+            ds.clear_ack(command::PROBE);
+            let probe = NegiconEvent::new(
+                NegiconEventType::Output,
+                command::PROBE,
+                u7::new(0),
+                0,
+                command::ANY_CONTROLLER,
+                0,
+            );
+            if let Err(e) = ds.request(probe) {
+                warn!("Could not queue probe for slot {}: {:?}", slot, e);
+            }
+            probed |= 1 << slot;
+        }
+        Self {
+            sent_us: time::now_us(),
+            probed,
+            pending: probed,
+        }
+    }
+
+    pub(crate) fn update(&mut self, downstreams: &[DownstreamDevice]) {
+        for (slot, ds) in downstreams.iter().enumerate() {
+            if ds.acknowledged(command::PROBE) {
+                self.pending &= !(1 << slot);
+            }
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.pending == 0
+    }
+
+    pub(crate) fn probed(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_SLOTS).filter(move |slot| self.probed & (1 << slot) != 0)
+    }
+
+    /// Time from queueing the probe to the answer, which includes waiting for the slot's next
+    /// transfer. None if the slot did not answer.
+    pub(crate) fn rtt_us(&self, slot: usize, ds: &DownstreamDevice) -> Option<u32> {
+        if self.pending & (1 << slot) != 0 {
+            return None;
+        }
+        Some(ds.ack_time_us(command::PROBE)?.wrapping_sub(self.sent_us))
+    }
+}
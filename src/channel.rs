@@ -0,0 +1,68 @@
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    task::{Context, Poll, Waker},
+};
+
+/// Bounded queue connecting the tasks of the executor.
+///
+/// Senders never wait, `try_send` hands the item back if the channel is full and the caller
+/// decides what to drop. There is a single receiving task per channel.
+pub(crate) struct Channel<T, const N: usize> {
+    queue: RefCell<VecDeque<T>>,
+    receiver: RefCell<Option<Waker>>,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            queue: RefCell::new(VecDeque::with_capacity(N)),
+            receiver: RefCell::new(None),
+        }
+    }
+
+    pub(crate) fn try_send(&self, item: T) -> Result<(), T> {
+        {
+            let mut queue = self.queue.borrow_mut();
+            if queue.len() == N {
+                return Err(item);
+            }
+            queue.push_back(item);
+        }
+        if let Some(waker) = self.receiver.borrow_mut().take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.borrow().is_empty()
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<T> {
+        self.queue.borrow_mut().pop_front()
+    }
+
+    /// Ready once the channel holds an item, without taking it.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.queue.borrow().is_empty() {
+            return Poll::Ready(());
+        }
+        self.receiver.replace(Some(cx.waker().clone()));
+        Poll::Pending
+    }
+
+    pub(crate) async fn recv(&self) -> T {
+        poll_fn(|cx| match self.try_recv() {
+            Some(item) => Poll::Ready(item),
+            None => {
+                self.receiver.replace(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
@@ -0,0 +1,20 @@
+/// Fletcher-16 checksum, which the host uses for firmware images.
+pub(crate) struct Fletcher16 {
+    sum1: u16,
+    sum2: u16,
+}
+
+impl Fletcher16 {
+    pub(crate) fn new() -> Self {
+        Self { sum1: 0, sum2: 0 }
+    }
+
+    pub(crate) fn add(&mut self, byte: u8) {
+        self.sum1 = (self.sum1 + byte as u16) % 255;
+        self.sum2 = (self.sum2 + self.sum1) % 255;
+    }
+
+    pub(crate) fn value(&self) -> u16 {
+        self.sum2 << 8 | self.sum1
+    }
+}
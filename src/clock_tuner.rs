@@ -0,0 +1,145 @@
+use crate::spi_downstream::SpiConfig;
+
+/// Transfers with a present module per measurement window.
+const WINDOW: u16 = 200;
+/// Invalid replies within a window at which the clock is slowed down.
+const ERROR_THRESHOLD: u16 = 4;
+/// Error-free windows after which the next faster clock is tried again.
+const STABLE_WINDOWS: u16 = 25;
+/// Each step doubles the divisor, so the slowest clock is a sixteenth of the configured one.
+const MAX_STEPS: u8 = 4;
+
+/// Slows down the bus clock of a slot while its replies are unreliable and speeds it up
+/// again once they have been clean for a while.
+pub(crate) struct ClockTuner {
+    base: SpiConfig,
+    /// Set for slots on a bus without a clock of its own to tune.
+    fixed: bool,
+    step: u8,
+    transfers: u16,
+    errors: u16,
+    stable: u16,
+    changed: bool,
+}
+
+impl ClockTuner {
+    pub(crate) fn new(base: SpiConfig) -> Self {
+        Self {
+            base,
+            fixed: false,
+            step: 0,
+            transfers: 0,
+            errors: 0,
+            stable: 0,
+            changed: false,
+        }
+    }
+
+    /// Replaces the configured timing, which restarts tuning from it.
+    pub(crate) fn set_base(&mut self, base: SpiConfig) {
+        self.base = base;
+        self.reset();
+        self.changed = true;
+    }
+
+    /// Keeps the configured timing for good and never reports a change.
+    pub(crate) fn fix(&mut self) {
+        self.reset();
+        self.fixed = true;
+        self.changed = false;
+    }
+
+    /// Changes only the sample delay, which does not affect the tuned clock.
+    pub(crate) fn set_sample_delay(&mut self, delay: u8) {
+        self.base.sample_delay = delay;
+    }
+
+    /// The configured timing, slowed down by the current step.
+    pub(crate) fn config(&self) -> SpiConfig {
+        SpiConfig {
+            clock_divisor: self.base.clock_divisor.saturating_mul(1 << self.step),
+            ..self.base
+        }
+    }
+
+    /// Counts the outcome of one transfer with a present module.
+    pub(crate) fn record(&mut self, valid: bool) {
+        if self.fixed {
+            return;
+        }
+        self.transfers += 1;
+        if !valid {
+            self.errors += 1;
+        }
+        if self.errors >= ERROR_THRESHOLD {
+            // Retested with the next window
+            self.step_down();
+            return;
+        }
+        if self.transfers < WINDOW {
+            return;
+        }
+        if self.errors == 0 {
+            self.stable += 1;
+        }
+        if self.stable >= STABLE_WINDOWS && self.step > 0 {
+            self.stable = 0;
+            self.step -= 1;
+            self.changed = true;
+        }
+        self.start_window();
+    }
+
+    /// Slows the clock down by one step and starts a new window. Returns false if it already
+    /// is at the slowest.
+    pub(crate) fn step_down(&mut self) -> bool {
+        self.stable = 0;
+        self.start_window();
+        if self.fixed || self.step == MAX_STEPS {
+            return false;
+        }
+        self.step += 1;
+        self.changed = true;
+        true
+    }
+
+    /// Moves on to the next step for the following probe of an empty slot, so a module that
+    /// only answers at a slower clock is still found.
+    pub(crate) fn next_probe(&mut self) {
+        if self.fixed {
+            return;
+        }
+        self.step = (self.step + 1) % (MAX_STEPS + 1);
+        self.start_window();
+    }
+
+    /// Reports the clock a module was just found at.
+    pub(crate) fn found(&mut self) {
+        self.stable = 0;
+        self.changed = !self.fixed;
+    }
+
+    /// Goes back to the configured timing.
+    pub(crate) fn reset(&mut self) {
+        if self.step > 0 {
+            self.changed = true;
+        }
+        self.step = 0;
+        self.stable = 0;
+        self.start_window();
+    }
+
+    /// Returns the divisor now in use if it changed since the last call.
+    pub(crate) fn take_change(&mut self) -> Option<u16> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        Some(self.config().clock_divisor)
+    }
+
+    fn start_window(&mut self) {
+        self.transfers = 0;
+        self.errors = 0;
+    }
+}
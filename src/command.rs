@@ -0,0 +1,441 @@
+use defmt::warn;
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use ux::u7;
+
+use crate::{
+    chain,
+    registry::{SlotMask, MAX_SLOTS},
+    spi_downstream::{DownstreamDevice, SpiConfig, SpiMode},
+    upstream::OverflowPolicy,
+};
+
+/// Event ids at or above this value are addressed to the controller itself instead of a module.
+/// Replies to these commands are sent back upstream as `Input` events with the same id.
+pub(crate) const COMMAND_ID_BASE: u16 = 0xff00;
+
+/// Resets every control on every present module. Also used as the id of the broadcast
+/// sent downstream and of the acknowledgement a module answers with.
+pub(crate) const RESET_ALL: u16 = 0xff01;
+
+/// Number of events an upstream had to drop, reported with the upstream index as slot.
+pub(crate) const LOST_EVENTS: u16 = 0xff02;
+
+/// Selects the upstream `OverflowPolicy`, given as the event value.
+pub(crate) const SET_OVERFLOW_POLICY: u16 = 0xff03;
+
+/// Sets how many scan ticks pass between two probes of an empty slot, given as the event value.
+pub(crate) const SET_PROBE_PERIOD: u16 = 0xff04;
+
+/// Switches the slot given as the event slot, or all slots, to pipelined transfers if the
+/// value is non-zero.
+pub(crate) const SET_PIPELINED: u16 = 0xff05;
+
+/// Sets the bus timing of the slot given as the event slot, or of all slots. The value is the
+/// clock divisor and the sub id the SPI mode. Clears the sample delay, so calibrate afterwards.
+pub(crate) const SET_SPI_CONFIG: u16 = 0xff06;
+
+/// Clock divisor a slot runs at, reported with the slot whenever it changes.
+pub(crate) const CLOCK_DIVISOR: u16 = 0xff07;
+
+/// Calibrates the MISO sample delay of the slot given as the event slot, or of all present
+/// slots. Reported per slot with the chosen delay as value, or -1 if no delay worked.
+pub(crate) const CALIBRATE: u16 = 0xff08;
+
+/// Reads the slot given as the event slot, or all slots, when its data ready line is asserted
+/// instead of polling it if the value is non-zero.
+pub(crate) const SET_ATTENTION: u16 = 0xff09;
+
+/// Starts a firmware update of the module in the event slot. The image length in bytes is
+/// given by the value as low and the sub id as high bits. Reported with the result.
+pub(crate) const MODULE_UPDATE_BEGIN: u16 = 0xff0a;
+
+/// Carries the next three image bytes in the value, high byte first, and the slot field.
+/// The sub id counts the events modulo 128. Reported with the progress in percent after each
+/// block written to the module; the host waits for that before sending more.
+pub(crate) const MODULE_UPDATE_DATA: u16 = 0xff0b;
+
+/// Ends the image with its Fletcher-16 checksum as value. Reported with the result once the
+/// module verified the image.
+pub(crate) const MODULE_UPDATE_END: u16 = 0xff0c;
+
+/// Starts an update of the controller's own firmware, with the image length encoded like
+/// `MODULE_UPDATE_BEGIN`. Reported with the result.
+pub(crate) const FIRMWARE_UPDATE_BEGIN: u16 = 0xff0d;
+
+/// Carries image bytes like `MODULE_UPDATE_DATA`. Reported with the progress in percent every
+/// twelve events; the host waits for that before sending more.
+pub(crate) const FIRMWARE_UPDATE_DATA: u16 = 0xff0e;
+
+/// Ends the image with its Fletcher-16 checksum as value. Reported with the result, after
+/// which the controller resets into the new image.
+pub(crate) const FIRMWARE_UPDATE_END: u16 = 0xff0f;
+
+/// Asks the controller for what is attached to it. Reported with the number of present slots,
+/// followed by a `TOPOLOGY_SLOT` report per present slot. The query is then passed on to each
+/// chained controller, which reports its own part of the tree under its own controller id.
+pub(crate) const TOPOLOGY: u16 = 0xff10;
+
+/// Asks whatever sits in a slot to describe itself. Sent downstream as a request to
+/// `ANY_CONTROLLER`; modules answer with their descriptor as value, controllers with their
+/// controller id in both the value and the controller id field, which modules leave at
+/// `MODULE_ID`.
+pub(crate) const DESCRIPTOR: u16 = 0xff11;
+
+/// What a present slot holds, reported with the slot and the module descriptor as value, or
+/// `NO_DESCRIPTOR` if the slot did not answer. For a chained controller the sub id is
+/// `SLOT_CONTROLLER` and the value its controller id.
+pub(crate) const TOPOLOGY_SLOT: u16 = 0xff12;
+
+/// Sent upstream by a chained controller without an id, with a random nonce as value. The
+/// root answers with `ASSIGN_ID`.
+pub(crate) const ID_REQUEST: u16 = 0xff13;
+
+/// Gives the id in the slot field to the unassigned controller whose nonce is the value.
+/// Reported by that controller under its new id once it took it.
+pub(crate) const ASSIGN_ID: u16 = 0xff14;
+
+/// Makes the addressed controller drop its id and request a new one.
+pub(crate) const RELEASE_ID: u16 = 0xff15;
+
+/// Id found behind more than one slot, reported with the id as value. Both controllers are
+/// sent `RELEASE_ID`.
+pub(crate) const DUPLICATE_ID: u16 = 0xff16;
+
+/// Reported with the slot that sent the controller's own events back to it, which are
+/// dropped from then on.
+pub(crate) const CHAIN_LOOP: u16 = 0xff17;
+
+/// Measures every hop between the controller and the controllers chained directly behind it,
+/// then passes the ping on to them. Reported with the number of hops, followed by one report
+/// per hop and `HOP_*` kind in the sub id, with the slot of the hop.
+pub(crate) const CHAIN_PING: u16 = 0xff18;
+
+/// Sent downstream as a request to `ANY_CONTROLLER` during a chain ping. The controller
+/// answers with the number of events it could not receive on that link since the last probe.
+pub(crate) const PROBE: u16 = 0xff19;
+
+/// Routes host outputs for the control given as the value to the slot given as the event slot,
+/// for controls a module has no input for, such as LEDs. A non-zero sub id removes the route.
+pub(crate) const MAP_OUTPUT: u16 = 0xff1a;
+
+/// Marks the control given as the value, of the controller given as the event slot, as
+/// relative, such as an encoder, if the sub id is zero and as absolute otherwise. Only
+/// relative controls are summed up by `MergeDeltas`.
+pub(crate) const SET_RELATIVE: u16 = 0xff1b;
+
+/// Sent up by a controller before interrupts go off for a flash write, with the time in
+/// milliseconds the controller above has to leave the link alone as value. Anything sent in
+/// the meantime would overflow the receive FIFO.
+pub(crate) const BUSY: u16 = 0xff1c;
+
+/// Sub id of a `TOPOLOGY_SLOT` report on a slot that holds a chained controller.
+pub(crate) const SLOT_CONTROLLER: u8 = 1;
+
+/// Round trip of a probe in microseconds, or -1 if it was not answered.
+pub(crate) const HOP_RTT_US: u8 = 0;
+/// Invalid replies the upper controller got on the hop since the last chain ping.
+pub(crate) const HOP_FAILED_TRANSFERS: u8 = 1;
+/// Events the lower controller could not receive on the hop, as it answered the probe.
+pub(crate) const HOP_RECEIVE_ERRORS: u8 = 2;
+
+pub(crate) const STATUS_OK: i16 = 0;
+pub(crate) const STATUS_FAILED: i16 = 1;
+
+/// Sub id of every report, so a report with a value of zero is not taken for a ping.
+const REPORT_SUB_ID: u8 = 1;
+
+/// Slot value used in a report that refers to the whole controller rather than a single slot.
+pub(crate) const ALL_SLOTS: u8 = 0xff;
+
+/// Controller id that addresses whichever controller receives the event.
+pub(crate) const ANY_CONTROLLER: u8 = 0xff;
+
+pub(crate) const NO_DESCRIPTOR: i16 = -1;
+
+pub(crate) enum Command {
+    ResetAll,
+    SetOverflowPolicy(OverflowPolicy),
+    SetProbePeriod(u16),
+    SetPipelined { slot: u8, enabled: bool },
+    SetSpiConfig { slot: u8, config: SpiConfig },
+    Calibrate { slot: u8 },
+    SetAttention { slot: u8, enabled: bool },
+    ModuleUpdateBegin { slot: u8, len: u32 },
+    ModuleUpdateData { seq: u8, bytes: [u8; 3] },
+    ModuleUpdateEnd { checksum: u16 },
+    FirmwareUpdateBegin { len: u32 },
+    FirmwareUpdateData { seq: u8, bytes: [u8; 3] },
+    FirmwareUpdateEnd { checksum: u16 },
+    Topology,
+    Descriptor,
+    AssignId { nonce: u16, id: u8 },
+    ReleaseId,
+    ChainPing,
+    Probe,
+    MapOutput { slot: u8, id: u16, mapped: bool },
+    SetRelative {
+        controller_id: u8,
+        id: u16,
+        relative: bool,
+    },
+}
+
+impl Command {
+    pub(crate) fn from_event(event: &NegiconEvent, controller_id: u8) -> Option<Self> {
+        let addressed = event.controller_id == controller_id
+            || (event.controller_id == ANY_CONTROLLER && is_neighbour_request(event.id));
+        if !matches!(event.event_type, NegiconEventType::Output) || !addressed {
+            return None;
+        }
+        match event.id {
+            RESET_ALL => Some(Command::ResetAll),
+            SET_OVERFLOW_POLICY => {
+                OverflowPolicy::from_value(event.value).map(Command::SetOverflowPolicy)
+            }
+            SET_PROBE_PERIOD if event.value > 0 => {
+                Some(Command::SetProbePeriod(event.value as u16))
+            }
+            SET_PIPELINED => Some(Command::SetPipelined {
+                slot: event.sequence,
+                enabled: event.value != 0,
+            }),
+            SET_SPI_CONFIG if event.value > 0 => {
+                SpiMode::from_value(u8::from(event.sub_id)).map(|mode| Command::SetSpiConfig {
+                    slot: event.sequence,
+                    config: SpiConfig {
+                        clock_divisor: event.value as u16,
+                        mode,
+                        sample_delay: 0,
+                    },
+                })
+            }
+            CALIBRATE => Some(Command::Calibrate {
+                slot: event.sequence,
+            }),
+            SET_ATTENTION => Some(Command::SetAttention {
+                slot: event.sequence,
+                enabled: event.value != 0,
+            }),
+            MODULE_UPDATE_BEGIN => Some(Command::ModuleUpdateBegin {
+                slot: event.sequence,
+                len: image_len(event),
+            }),
+            MODULE_UPDATE_DATA => Some(Command::ModuleUpdateData {
+                seq: u8::from(event.sub_id),
+                bytes: image_bytes(event),
+            }),
+            MODULE_UPDATE_END => Some(Command::ModuleUpdateEnd {
+                checksum: event.value as u16,
+            }),
+            FIRMWARE_UPDATE_BEGIN => Some(Command::FirmwareUpdateBegin {
+                len: image_len(event),
+            }),
+            FIRMWARE_UPDATE_DATA => Some(Command::FirmwareUpdateData {
+                seq: u8::from(event.sub_id),
+                bytes: image_bytes(event),
+            }),
+            FIRMWARE_UPDATE_END => Some(Command::FirmwareUpdateEnd {
+                checksum: event.value as u16,
+            }),
+            TOPOLOGY => Some(Command::Topology),
+            DESCRIPTOR => Some(Command::Descriptor),
+            ASSIGN_ID => Some(Command::AssignId {
+                nonce: event.value as u16,
+                id: event.sequence,
+            }),
+            RELEASE_ID => Some(Command::ReleaseId),
+            CHAIN_PING => Some(Command::ChainPing),
+            PROBE => Some(Command::Probe),
+            MAP_OUTPUT => Some(Command::MapOutput {
+                slot: event.sequence,
+                id: event.value as u16,
+                mapped: u8::from(event.sub_id) == 0,
+            }),
+            SET_RELATIVE => Some(Command::SetRelative {
+                controller_id: event.sequence,
+                id: event.value as u16,
+                relative: u8::from(event.sub_id) == 0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn image_len(event: &NegiconEvent) -> u32 {
+    (u8::from(event.sub_id) as u32) << 16 | event.value as u16 as u32
+}
+
+fn image_bytes(event: &NegiconEvent) -> [u8; 3] {
+    let [high, low] = event.value.to_be_bytes();
+    [high, low, event.sequence]
+}
+
+pub(crate) fn is_command(id: u16) -> bool {
+    id >= COMMAND_ID_BASE
+}
+
+/// Returns true for reports chained controllers send on their own or on behalf of a command
+/// that was passed on to them by another controller.
+pub(crate) fn is_chain_report(id: u16) -> bool {
+    matches!(
+        id,
+        TOPOLOGY | TOPOLOGY_SLOT | ID_REQUEST | ASSIGN_ID | DUPLICATE_ID | CHAIN_LOOP | CHAIN_PING
+    )
+}
+
+/// Returns true for requests a controller sends to whichever controller is chained behind a
+/// slot. Their answers are meant for that controller, not the host.
+pub(crate) fn is_neighbour_request(id: u16) -> bool {
+    matches!(id, DESCRIPTOR | PROBE)
+}
+
+/// Builds the event that tells the host the outcome of `command` for `slot`.
+pub(crate) fn report(command: u16, controller_id: u8, slot: u8, status: i16) -> NegiconEvent {
+    NegiconEvent::new(
+        NegiconEventType::Input,
+        command,
+        u7::new(REPORT_SUB_ID),
+        status,
+        controller_id,
+        slot,
+    )
+}
+
+/// Returns the id of the controller in `ds` if it answered the last descriptor request as one.
+pub(crate) fn chained_controller(ds: &DownstreamDevice) -> Option<u8> {
+    ds.ack(DESCRIPTOR)
+        .map(|ack| ack.controller_id)
+        .filter(|id| *id != chain::MODULE_ID)
+}
+
+/// Builds the report of one `kind` of measurement of the hop behind `slot`.
+pub(crate) fn hop_report(controller_id: u8, slot: u8, kind: u8, value: i16) -> NegiconEvent {
+    NegiconEvent::new(
+        NegiconEventType::Input,
+        CHAIN_PING,
+        u7::new(kind),
+        value,
+        controller_id,
+        slot,
+    )
+}
+
+/// Tracks a reset broadcast until every targeted slot has acknowledged it or the deadline passed.
+pub(crate) struct ResetAll {
+    pending: SlotMask,
+    /// Slots of chained controllers that reported a failed reset of their own slots.
+    failed: SlotMask,
+}
+
+impl ResetAll {
+    pub(crate) fn start(downstreams: &mut [DownstreamDevice], controller_id: u8) -> Self {
+        let mut pending: SlotMask = 0;
+        for (slot, ds) in downstreams.iter_mut().enumerate() {
+            if !ds.is_present() {
+                continue;
+            }
+            ds.clear_ack(RESET_ALL);
+            // A chained controller only takes the reset as addressed to itself
+            let target = chained_controller(ds).unwrap_or(controller_id);
+            let reset = NegiconEvent::new(
+                NegiconEventType::Output,
+                RESET_ALL,
+                u7::new(0),
+                0,
+                target,
+                0,
+            );
+            if let Err(e) = ds.request(reset) {
+                // Never acknowledged, so it ends up in the failure report
+                warn!("Could not queue reset for slot {}: {:?}", slot, e);
+            }
+            pending |= 1 << slot;
+        }
+        Self { pending, failed: 0 }
+    }
+
+    /// Clears all slots that have acknowledged the reset since it was started. A chained
+    /// controller reports each of its slots that failed first and its overall status last.
+    pub(crate) fn update(&mut self, downstreams: &[DownstreamDevice]) {
+        for (slot, ds) in downstreams.iter().enumerate() {
+            let ack = match ds.ack(RESET_ALL) {
+                Some(ack) => ack,
+                None => continue,
+            };
+            if chained_controller(ds).is_some() {
+                if ack.sequence != ALL_SLOTS {
+                    continue;
+                }
+                if ack.value != STATUS_OK {
+                    self.failed |= 1 << slot;
+                }
+            }
+            self.pending &= !(1 << slot);
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// Returns the slots that did not confirm the reset.
+    pub(crate) fn failed_slots(&self) -> impl Iterator<Item = u8> + '_ {
+        let failed = self.pending | self.failed;
+        (0..MAX_SLOTS as u8).filter(move |slot| failed & (1 << slot) != 0)
+    }
+}
+
+/// Collects the descriptors of all present slots for a topology report until every slot
+/// answered or the deadline passed.
+pub(crate) struct Topology {
+    pending: SlotMask,
+}
+
+impl Topology {
+    pub(crate) fn start(downstreams: &mut [DownstreamDevice]) -> Self {
+        let mut pending: SlotMask = 0;
+        for (slot, ds) in downstreams.iter_mut().enumerate() {
+            if !ds.is_present() {
+                continue;
+            }
+            ds.clear_ack(DESCRIPTOR);
+            let request = NegiconEvent::new(
+                NegiconEventType::Output,
+                DESCRIPTOR,
+                u7::new(0),
+                0,
+                ANY_CONTROLLER,
+                0,
+            );
+            if let Err(e) = ds.request(request) {
+                // Reported without a descriptor
+                warn!(
+                    "Could not queue descriptor request for slot {}: {:?}",
+                    slot, e
+                );
+            }
+            pending |= 1 << slot;
+        }
+        Self { pending }
+    }
+
+    /// Clears all slots that have answered since the query was started.
+    pub(crate) fn update(&mut self, downstreams: &[DownstreamDevice]) {
+        for (slot, ds) in downstreams.iter().enumerate() {
+            if ds.acknowledged(DESCRIPTOR) {
+                self.pending &= !(1 << slot);
+            }
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+/// Descriptor `ds` answered with during the last topology query.
+pub(crate) fn descriptor(ds: &DownstreamDevice) -> i16 {
+    ds.ack(DESCRIPTOR)
+        .map(|ack| ack.value)
+        .unwrap_or(NO_DESCRIPTOR)
+}
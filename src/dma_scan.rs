@@ -0,0 +1,229 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use cortex_m::interrupt::Mutex;
+
+use negicon_protocol::make_u32;
+use rp2040_hal::{
+    dma::SingleChannel,
+    pac::{self, interrupt},
+};
+
+use crate::spi_downstream::{self, DmaTargets, DownstreamDevice, SpiConfig};
+
+const MAX_SLOTS: usize = 32;
+/// Words written to the data state machine per slot: the word count minus one and one event.
+const TX_WORDS: usize = 3;
+const RX_WORDS: usize = 2;
+
+static SCAN_DONE: AtomicBool = AtomicBool::new(false);
+static SCAN_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+/// Runs a scan of several slots on three DMA channels, without the CPU feeding the FIFOs.
+///
+/// One channel feeds the chip selects to the cs state machine, one feeds the events to the
+/// data state machine and one drains the replies into `rx_table`. The state machines pace
+/// each other through PIO IRQs, so the channels only need their DREQs. The rx channel raises
+/// DMA_IRQ_0 once the last reply is in, while the end-of-transfer interrupt of the PIO stays
+/// masked for the whole scan.
+///
+/// The bus timing cannot change in the middle of a scan, so all slots of a scan share the
+/// `SpiConfig` of the first one added.
+pub(crate) struct DmaScanner<CS: SingleChannel, TX: SingleChannel, RX: SingleChannel> {
+    cs_ch: CS,
+    tx_ch: TX,
+    rx_ch: RX,
+    cs_table: &'static mut [u32; MAX_SLOTS],
+    tx_table: &'static mut [u32; MAX_SLOTS * TX_WORDS],
+    rx_table: &'static mut [u32; MAX_SLOTS * RX_WORDS],
+    slots: [u8; MAX_SLOTS],
+    len: usize,
+    config: Option<SpiConfig>,
+    /// PIO block of the running scan.
+    pio: usize,
+    busy: bool,
+    finished: bool,
+}
+
+impl<CS: SingleChannel, TX: SingleChannel, RX: SingleChannel> DmaScanner<CS, TX, RX> {
+    pub(crate) fn new(cs_ch: CS, tx_ch: TX, mut rx_ch: RX) -> Self {
+        rx_ch.enable_irq0();
+        unsafe {
+            pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0);
+        }
+        Self {
+            cs_ch,
+            tx_ch,
+            rx_ch,
+            cs_table: cortex_m::singleton!(: [u32; MAX_SLOTS] = [0; MAX_SLOTS]).unwrap(),
+            tx_table:
+                cortex_m::singleton!(: [u32; MAX_SLOTS * TX_WORDS] = [0; MAX_SLOTS * TX_WORDS])
+                    .unwrap(),
+            rx_table:
+                cortex_m::singleton!(: [u32; MAX_SLOTS * RX_WORDS] = [0; MAX_SLOTS * RX_WORDS])
+                    .unwrap(),
+            slots: [0; MAX_SLOTS],
+            len: 0,
+            config: None,
+            pio: 0,
+            busy: false,
+            finished: false,
+        }
+    }
+
+    pub(crate) fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Timing of the bus during the prepared scan, `None` while it is empty.
+    pub(crate) fn spi_config(&self) -> Option<SpiConfig> {
+        self.config
+    }
+
+    /// Adds the next packet of `ds` to the scan being prepared. Returns false if the slot
+    /// could not be added, because the scan is full, the slot needs a different bus timing or
+    /// its module takes frames, which the fixed-size scan cannot carry.
+    pub(crate) fn add(&mut self, slot: usize, ds: &mut DownstreamDevice) -> bool {
+        if self.finished {
+            self.len = 0;
+            self.config = None;
+            self.finished = false;
+        }
+        if self.busy || self.len == MAX_SLOTS || ds.frame_words() > 2 {
+            return false;
+        }
+        match self.config {
+            Some(config) if config != ds.spi_config() => return false,
+            _ => self.config = Some(ds.spi_config()),
+        }
+        let packet = ds.next_packet();
+        self.cs_table[self.len] = ds.cs() as u32;
+        let tx = &mut self.tx_table[self.len * TX_WORDS..(self.len + 1) * TX_WORDS];
+        tx[0] = RX_WORDS as u32 - 1;
+        tx[1] = make_u32(packet[0], packet[1], packet[2], packet[3]);
+        tx[2] = make_u32(packet[4], packet[5], packet[6], packet[7]);
+        self.slots[self.len] = slot as u8;
+        self.len += 1;
+        true
+    }
+
+    /// Starts the prepared scan. Does nothing if no slot was added.
+    pub(crate) fn start(&mut self, targets: &DmaTargets) {
+        if self.busy || self.finished || self.len == 0 {
+            return;
+        }
+        SCAN_DONE.store(false, Ordering::Release);
+        self.busy = true;
+        self.pio = targets.pio;
+        spi_downstream::set_transfer_irq(self.pio, false);
+        // The rx channel goes first so no reply can be missed
+        start_channel(
+            &self.rx_ch,
+            targets.data_rx.0,
+            self.rx_table.as_ptr() as u32,
+            self.len * RX_WORDS,
+            targets.data_rx.1,
+            (false, true),
+        );
+        start_channel(
+            &self.tx_ch,
+            self.tx_table.as_ptr() as u32,
+            targets.data_tx.0,
+            self.len * TX_WORDS,
+            targets.data_tx.1,
+            (true, false),
+        );
+        start_channel(
+            &self.cs_ch,
+            self.cs_table.as_ptr() as u32,
+            targets.cs_tx.0,
+            self.len,
+            targets.cs_tx.1,
+            (true, false),
+        );
+    }
+
+    /// Ready once the running scan has finished. Stays pending while no scan is running.
+    pub(crate) fn poll_done(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.busy {
+            return Poll::Pending;
+        }
+        cortex_m::interrupt::free(|cs| {
+            SCAN_WAKER.borrow(cs).replace(Some(cx.waker().clone()));
+        });
+        if SCAN_DONE.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Hands the replies of a finished scan to their devices and returns the scanned slots.
+    /// Returns `None` while the scan is still running.
+    pub(crate) fn finish(&mut self, downstreams: &mut [DownstreamDevice]) -> Option<&[u8]> {
+        if !self.busy || !SCAN_DONE.load(Ordering::Acquire) {
+            return None;
+        }
+        for (i, slot) in self.slots[..self.len].iter().enumerate() {
+            let rx = &self.rx_table[i * RX_WORDS..(i + 1) * RX_WORDS];
+            let mut reply = [0u8; 8];
+            reply[..4].copy_from_slice(&rx[0].to_be_bytes());
+            reply[4..].copy_from_slice(&rx[1].to_be_bytes());
+            // Empty slots are expected to fail, their absence is tracked by the device
+            let _ = downstreams[*slot as usize].complete(&reply);
+        }
+        spi_downstream::set_transfer_irq(self.pio, true);
+        self.busy = false;
+        self.finished = true;
+        Some(&self.slots[..self.len])
+    }
+}
+
+/// Configures a channel for 32 bit transfers paced by `dreq` and starts it.
+/// `incr` selects whether the read and the write address advance.
+fn start_channel<CH: SingleChannel>(
+    ch: &CH,
+    read: u32,
+    write: u32,
+    count: usize,
+    dreq: u8,
+    incr: (bool, bool),
+) {
+    let regs = ch.ch();
+    regs.ch_read_addr.write(|w| unsafe { w.bits(read) });
+    regs.ch_write_addr.write(|w| unsafe { w.bits(write) });
+    regs.ch_trans_count
+        .write(|w| unsafe { w.bits(count as u32) });
+    regs.ch_ctrl_trig.write(|w| unsafe {
+        w.data_size()
+            .size_word()
+            .incr_read()
+            .bit(incr.0)
+            .incr_write()
+            .bit(incr.1)
+            .treq_sel()
+            .bits(dreq)
+            // chaining to itself disables chaining
+            .chain_to()
+            .bits(ch.id())
+            .en()
+            .set_bit()
+    });
+}
+
+#[interrupt]
+fn DMA_IRQ_0() {
+    // Only the rx channel of the scanner raises DMA_IRQ_0
+    let dma = unsafe { &*pac::DMA::ptr() };
+    let status = dma.ints0.read().bits();
+    dma.ints0.write(|w| unsafe { w.bits(status) });
+    SCAN_DONE.store(true, Ordering::Release);
+    cortex_m::interrupt::free(|cs| {
+        if let Some(waker) = SCAN_WAKER.borrow(cs).take() {
+            waker.wake();
+        }
+    });
+}
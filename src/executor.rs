@@ -0,0 +1,66 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, RawWaker, RawWakerVTable, Waker},
+};
+
+const MAX_TASKS: usize = 8;
+
+/// Set by a task's waker, cleared right before the task is polled.
+/// Every task starts out ready so it gets polled once and can register its wakers.
+static READY: [AtomicBool; MAX_TASKS] = [
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+];
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+unsafe fn clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    READY[data as usize].store(true, Ordering::Release);
+}
+
+unsafe fn drop(_data: *const ()) {}
+
+/// Runs `tasks` forever on the current core.
+///
+/// A task is only polled after its waker was called. Wakers are usually called from interrupt
+/// handlers, so the core sleeps with WFI whenever no task is ready.
+pub(crate) fn run(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> ! {
+    assert!(tasks.len() <= MAX_TASKS);
+    loop {
+        for (index, task) in tasks.iter_mut().enumerate() {
+            // No compare-and-swap on the M0+, so take the flag with interrupts masked
+            let ready = cortex_m::interrupt::free(|_| {
+                let ready = READY[index].load(Ordering::Acquire);
+                READY[index].store(false, Ordering::Release);
+                ready
+            });
+            if !ready {
+                continue;
+            }
+            let waker = unsafe { Waker::from_raw(RawWaker::new(index as *const (), &VTABLE)) };
+            let mut cx = Context::from_waker(&waker);
+            let _ = task.as_mut().poll(&mut cx);
+        }
+        cortex_m::interrupt::free(|_| {
+            if !READY[..tasks.len()]
+                .iter()
+                .any(|ready| ready.load(Ordering::Acquire))
+            {
+                // Returns on the pending interrupt even though interrupts are masked
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+}
@@ -0,0 +1,112 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use defmt::{debug, warn};
+use rp2040_project_template::{
+    boot_state::{BootState, Record, MAX_IMAGE_BYTES, STAGING_OFFSET},
+    flash::{self, SECTOR_SIZE},
+};
+
+use crate::{checksum::Fletcher16, module_update::UpdateError};
+
+/// Image events the host may send before it waits for a progress report.
+const ACK_EVENTS: u32 = 12;
+
+/// Returns true if the running image still has to confirm it works.
+pub(crate) fn is_unconfirmed() -> bool {
+    Record::load().state == BootState::TrialBooted
+}
+
+/// Marks the running image as good, which keeps it from being rolled back on the next reset.
+pub(crate) fn confirm_boot() {
+    let mut record = Record::load();
+    if record.state == BootState::TrialBooted {
+        debug!("New firmware confirmed");
+        record.store(BootState::Idle, record.len);
+    }
+}
+
+/// Receives a new controller image from the host into the staging area. The host sends the
+/// image three bytes per event like a module image, see `ModuleUpdate`.
+pub(crate) struct FirmwareUpdate {
+    len: u32,
+    received: u32,
+    events: u32,
+    seq: u8,
+    sector: Vec<u8>,
+    checksum: Fletcher16,
+}
+
+impl FirmwareUpdate {
+    pub(crate) fn start(len: u32) -> Result<Self, UpdateError> {
+        if len == 0 || len > MAX_IMAGE_BYTES {
+            return Err(UpdateError::TooLong);
+        }
+        Ok(Self {
+            len,
+            received: 0,
+            events: 0,
+            seq: 0,
+            sector: Vec::with_capacity(SECTOR_SIZE),
+            checksum: Fletcher16::new(),
+        })
+    }
+
+    /// Adds the next bytes of the image. Returns the progress in percent every `ACK_EVENTS`
+    /// events, which tells the host to send the next ones.
+    pub(crate) fn push(&mut self, seq: u8, bytes: [u8; 3]) -> Result<Option<u8>, UpdateError> {
+        if seq != self.seq {
+            return Err(UpdateError::OutOfSequence);
+        }
+        self.seq = (self.seq + 1) & 0x7f;
+        self.events += 1;
+        // The last event may carry padding beyond the image
+        let remaining = self.len - self.received;
+        for byte in bytes.iter().take(remaining as usize) {
+            self.checksum.add(*byte);
+            self.sector.push(*byte);
+            self.received += 1;
+            if self.sector.len() == SECTOR_SIZE {
+                self.write_sector();
+            }
+        }
+        if self.events.is_multiple_of(ACK_EVENTS) || self.received == self.len {
+            return Ok(Some((self.received as u64 * 100 / self.len as u64) as u8));
+        }
+        Ok(None)
+    }
+
+    /// Returns true if the next image event completes a sector, which is then written to flash.
+    pub(crate) fn fills_sector(&self) -> bool {
+        let remaining = (self.len - self.received).min(3) as usize;
+        remaining > 0 && self.sector.len() + remaining >= SECTOR_SIZE
+    }
+
+    /// Checks the image against the host's checksum, both as received and as read back from
+    /// flash, and schedules the swap for the next reset.
+    pub(crate) fn finish(mut self, checksum: u16) -> Result<(), UpdateError> {
+        if self.received != self.len || self.checksum.value() != checksum {
+            return Err(UpdateError::Checksum);
+        }
+        if !self.sector.is_empty() {
+            self.write_sector();
+        }
+        let mut stored = Fletcher16::new();
+        for byte in flash::read(STAGING_OFFSET, self.len as usize) {
+            stored.add(*byte);
+        }
+        if stored.value() != checksum {
+            warn!("Staged firmware does not match what was received");
+            return Err(UpdateError::Checksum);
+        }
+        Record::load().store(BootState::SwapPending, self.len);
+        Ok(())
+    }
+
+    fn write_sector(&mut self) {
+        let offset = (self.received as usize - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        self.sector.resize(SECTOR_SIZE, 0xff);
+        flash::write(STAGING_OFFSET + offset as u32, &self.sector);
+        self.sector.clear();
+    }
+}
@@ -0,0 +1,100 @@
+use core::ptr;
+
+use rp2040_hal::rom_data;
+
+/// Address the flash is mapped to by XIP.
+pub const FLASH_BASE: u32 = 0x1000_0000;
+/// Smallest unit the flash can erase.
+pub const SECTOR_SIZE: usize = 4096;
+/// Smallest unit the flash can program.
+pub const PAGE_SIZE: usize = 256;
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xd8;
+const BOOT2_WORDS: usize = 64;
+
+/// Copy of boot2, which restores the fast XIP setup after the flash was written.
+static mut BOOT2_RAM: [u32; BOOT2_WORDS] = [0; BOOT2_WORDS];
+
+/// ROM flash routines. They have to be looked up while XIP still works.
+struct RomFunctions {
+    connect: unsafe extern "C" fn(),
+    exit_xip: unsafe extern "C" fn(),
+    erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    program: unsafe extern "C" fn(u32, *const u8, usize),
+    flush: unsafe extern "C" fn(),
+    enter_xip: unsafe extern "C" fn(),
+}
+
+impl RomFunctions {
+    fn get() -> Self {
+        unsafe {
+            let boot2 = ptr::addr_of_mut!(BOOT2_RAM) as *mut u32;
+            for i in 0..BOOT2_WORDS {
+                boot2
+                    .add(i)
+                    .write_volatile(ptr::read_volatile((FLASH_BASE as *const u32).add(i)));
+            }
+            Self {
+                connect: rom_data::connect_internal_flash::ptr(),
+                exit_xip: rom_data::flash_exit_xip::ptr(),
+                erase: rom_data::flash_range_erase::ptr(),
+                program: rom_data::flash_range_program::ptr(),
+                flush: rom_data::flash_flush_cache::ptr(),
+                // Thumb code, so the lowest address bit is set
+                enter_xip: core::mem::transmute::<usize, unsafe extern "C" fn()>(
+                    boot2 as usize + 1,
+                ),
+            }
+        }
+    }
+}
+
+/// Erases the sectors at `offset` from the start of flash and programs `data` into them.
+/// `offset` and the length of `data` have to be multiples of `SECTOR_SIZE`.
+pub fn write(offset: u32, data: &[u8]) {
+    debug_assert!(
+        (offset as usize).is_multiple_of(SECTOR_SIZE) && data.len().is_multiple_of(SECTOR_SIZE)
+    );
+    let rom = RomFunctions::get();
+    cortex_m::interrupt::free(|_| unsafe {
+        write_ram(&rom, offset, data.as_ptr(), data.len());
+    });
+}
+
+/// Reads `len` bytes at `offset` from the start of flash through XIP.
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((FLASH_BASE + offset) as *const u8, len) }
+}
+
+/// Programs `data` at `offset` from the start of flash without erasing it first, which can
+/// only clear bits. `offset` and the length of `data` have to be multiples of `PAGE_SIZE`.
+pub fn program(offset: u32, data: &[u8]) {
+    debug_assert!(
+        (offset as usize).is_multiple_of(PAGE_SIZE) && data.len().is_multiple_of(PAGE_SIZE)
+    );
+    let rom = RomFunctions::get();
+    cortex_m::interrupt::free(|_| unsafe {
+        program_ram(&rom, offset, data.as_ptr(), data.len());
+    });
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_ram(rom: &RomFunctions, offset: u32, data: *const u8, len: usize) {
+    (rom.connect)();
+    (rom.exit_xip)();
+    (rom.erase)(offset, len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    (rom.program)(offset, data, len);
+    (rom.flush)();
+    (rom.enter_xip)();
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program_ram(rom: &RomFunctions, offset: u32, data: *const u8, len: usize) {
+    (rom.connect)();
+    (rom.exit_xip)();
+    (rom.program)(offset, data, len);
+    (rom.flush)();
+    (rom.enter_xip)();
+}
@@ -0,0 +1,108 @@
+use core::convert::Infallible;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::spi_downstream::{DownstreamError, DownstreamInterface, SpiConfig};
+
+/// Downstream bus for a single module on SPI driven from plain GPIOs, for boards that ran out
+/// of SPI peripherals. The module sits on chip select 0.
+///
+/// The clock divisor and mode of `SpiConfig` apply like on the PIO bus, the sample delay does
+/// not, as MISO is read half a clock period after it changes anyway.
+pub(crate) struct GpioSpiDownstream<SCK, MOSI, MISO, CS> {
+    sck: SCK,
+    mosi: MOSI,
+    miso: MISO,
+    cs: CS,
+    config: SpiConfig,
+}
+
+impl<SCK, MOSI, MISO, CS> GpioSpiDownstream<SCK, MOSI, MISO, CS>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    CS: OutputPin<Error = Infallible>,
+{
+    pub(crate) fn new(sck: SCK, mosi: MOSI, miso: MISO, cs: CS) -> Self {
+        let mut downstream = Self {
+            sck,
+            mosi,
+            miso,
+            cs,
+            config: SpiConfig::default(),
+        };
+        let _ = downstream.cs.set_high();
+        downstream.set_sck(false);
+        downstream
+    }
+
+    /// Drives SCK to its idle level if `active` is false, to the other one otherwise.
+    fn set_sck(&mut self, active: bool) {
+        let _ = if active != self.config.mode.cpol() {
+            self.sck.set_high()
+        } else {
+            self.sck.set_low()
+        };
+    }
+
+    /// Waits half a bit. A bit takes two cycles of the divided clock on the PIO bus.
+    fn half_bit(&self) {
+        cortex_m::asm::delay(self.config.clock_divisor as u32);
+    }
+
+    fn transfer_word(&mut self, word: u32) -> u32 {
+        let mut reply = 0u32;
+        for bit in (0..32).rev() {
+            let out = word & (1 << bit) != 0;
+            if self.config.mode.cpha() {
+                self.set_sck(true);
+            }
+            let _ = if out {
+                self.mosi.set_high()
+            } else {
+                self.mosi.set_low()
+            };
+            self.half_bit();
+            // Sampled on the leading edge in phase 0 and on the trailing one in phase 1
+            self.set_sck(!self.config.mode.cpha());
+            reply = reply << 1 | self.miso.is_high().unwrap_or(true) as u32;
+            self.half_bit();
+            if !self.config.mode.cpha() {
+                self.set_sck(false);
+            }
+        }
+        reply
+    }
+}
+
+impl<SCK, MOSI, MISO, CS> DownstreamInterface for GpioSpiDownstream<SCK, MOSI, MISO, CS>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    CS: OutputPin<Error = Infallible>,
+{
+    fn configure(&mut self, config: SpiConfig) {
+        self.config = config;
+        // Only ever called between transfers, with the module deselected
+        self.set_sck(false);
+    }
+
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError> {
+        if cs != 0 || rx.len() != tx.len() {
+            return Err(DownstreamError::InvalidLength);
+        }
+        let _ = self.cs.set_low();
+        self.half_bit();
+        for (word, reply) in tx.iter().zip(rx.iter_mut()) {
+            *reply = self.transfer_word(*word);
+        }
+        let _ = self.cs.set_high();
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use core::convert::Infallible;
+use embedded_hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+use fugit::RateExtU32;
+use rp2040_hal::{
+    pac,
+    spi::{Enabled, Spi, ValidSpiPinout},
+};
+
+use crate::spi_downstream::{DownstreamError, DownstreamInterface, SpiConfig};
+
+/// Downstream bus for a single module on the SPI1 peripheral, with its chip select on a GPIO.
+/// The module sits on chip select 0.
+///
+/// The clock divisor and mode of `SpiConfig` apply like on the PIO bus, the sample delay does
+/// not, as the peripheral samples at a fixed point.
+pub(crate) struct HwSpiDownstream<P: ValidSpiPinout<pac::SPI1>, CS> {
+    spi: Spi<Enabled, pac::SPI1, P, 8>,
+    cs: CS,
+    /// Frequency the peripheral is clocked with, in Hz.
+    peripheral_hz: u32,
+    config: Option<SpiConfig>,
+}
+
+impl<P: ValidSpiPinout<pac::SPI1>, CS: OutputPin<Error = Infallible>> HwSpiDownstream<P, CS> {
+    pub(crate) fn new(spi: Spi<Enabled, pac::SPI1, P, 8>, cs: CS, peripheral_hz: u32) -> Self {
+        let mut downstream = Self {
+            spi,
+            cs,
+            peripheral_hz,
+            config: None,
+        };
+        let _ = downstream.cs.set_high();
+        downstream.configure(SpiConfig::default());
+        downstream
+    }
+}
+
+impl<P: ValidSpiPinout<pac::SPI1>, CS: OutputPin<Error = Infallible>> DownstreamInterface
+    for HwSpiDownstream<P, CS>
+{
+    fn configure(&mut self, config: SpiConfig) {
+        if self.config == Some(config) {
+            return;
+        }
+        self.config = Some(config);
+        // A bit takes two cycles of the divided clock on the PIO bus
+        let divisor = (config.clock_divisor as u32).max(1) * 2;
+        self.spi
+            .set_baudrate(self.peripheral_hz.Hz(), (self.peripheral_hz / divisor).Hz());
+        // The HAL only sets the mode when the peripheral is initialized
+        let regs = unsafe { &*pac::SPI1::ptr() };
+        regs.sspcr1.modify(|_, w| w.sse().clear_bit());
+        regs.sspcr0.modify(|_, w| {
+            w.spo()
+                .bit(config.mode.cpol())
+                .sph()
+                .bit(config.mode.cpha())
+        });
+        regs.sspcr1.modify(|_, w| w.sse().set_bit());
+    }
+
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError> {
+        if cs != 0 || rx.len() != tx.len() {
+            return Err(DownstreamError::InvalidLength);
+        }
+        let _ = self.cs.set_low();
+        for (word, reply) in tx.iter().zip(rx.iter_mut()) {
+            let mut bytes = word.to_be_bytes();
+            // The peripheral never fails a transfer
+            let _ = self.spi.transfer(&mut bytes);
+            *reply = u32::from_be_bytes(bytes);
+        }
+        let _ = self.cs.set_high();
+        Ok(())
+    }
+}
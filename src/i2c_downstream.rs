@@ -0,0 +1,427 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+
+use defmt::{debug, warn, Format};
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use rp2040_hal::{
+    i2c::Controller,
+    pac::{self, i2c0::RegisterBlock},
+    I2C,
+};
+use ux::u7;
+
+use crate::{
+    chain, command,
+    spi_downstream::{self, DownstreamError, DownstreamInterface, MAX_FRAME_WORDS},
+    time,
+};
+
+/// 7 bit addresses outside the ranges the I2C specification reserves.
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+/// Slots of the bus. Modules take the first free one when they are found.
+pub(crate) const I2C_SLOTS: usize = 8;
+/// Addresses tried by each rescan, which keeps it short enough to run between scan ticks.
+const RESCAN_ADDRESSES: u8 = 8;
+/// Failed transfers in a row after which a module counts as removed and its slot is freed.
+const REMOVED_AFTER: u8 = 16;
+/// Longest a byte may take, clock stretching included, before the transfer is given up, in
+/// microseconds. A byte takes about 23 µs at 400 kHz.
+const BYTE_TIMEOUT_US: u32 = 1000;
+/// Half a clock period of the bus recovery, in microseconds.
+const RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+#[derive(Format)]
+pub(crate) enum I2cError {
+    /// The module did not acknowledge.
+    Nack,
+    /// The bus stopped moving, held low by a module.
+    Timeout,
+}
+
+#[derive(Clone, Copy)]
+struct Module {
+    address: u8,
+    failures: u8,
+    /// Index of the adapter that drives the module, if it does not speak Negicon itself.
+    adapter: Option<usize>,
+}
+
+/// Register access of the bus, handed to an `I2cAdapter`.
+pub(crate) trait I2cBus {
+    /// Writes `tx` to the module at `address`, then reads `rx` after a repeated start.
+    fn write_read(&mut self, address: u8, tx: &[u8], rx: &mut [u8]) -> Result<(), I2cError>;
+}
+
+/// Drives a module that does not send Negicon frames, such as an encoder or a touch sensor of
+/// another vendor, through its registers. Every adapter is asked about each module the scan
+/// finds before it is taken for one that speaks Negicon.
+pub(crate) trait I2cAdapter {
+    /// Returns true if the module at `address` is one this adapter drives.
+    fn detect(&mut self, bus: &mut dyn I2cBus, address: u8) -> bool;
+
+    /// Passes on an output the host sent to control `id` of the module.
+    fn output(
+        &mut self,
+        bus: &mut dyn I2cBus,
+        address: u8,
+        id: u16,
+        value: i16,
+    ) -> Result<(), I2cError>;
+
+    /// Reads the module. Returns an input event if one of its controls changed.
+    fn input(
+        &mut self,
+        bus: &mut dyn I2cBus,
+        address: u8,
+    ) -> Result<Option<NegiconEvent>, I2cError>;
+}
+
+/// Downstream bus for modules that only speak I2C. Every module found by the address scan
+/// gets a slot, which stands in for the chip select of the SPI bus and stays the same until
+/// the module is removed.
+///
+/// A transfer writes the frame and reads the reply of the same length with a repeated start,
+/// so a module answers each frame within its own transaction like on SPI. Modules driven by an
+/// `I2cAdapter` get the event of the frame passed on and answer with the event the adapter
+/// makes of their registers. Transfers drive the controller registers directly, as the
+/// blocking transfers of the HAL wait forever for a bus that is held low.
+pub(crate) struct I2cDownstream<P> {
+    /// Owns the controller and its pins, which the HAL set up.
+    _bus: I2C<pac::I2C0, P, Controller>,
+    sda: u8,
+    scl: u8,
+    slots: [Option<Module>; I2C_SLOTS],
+    adapters: Vec<Box<dyn I2cAdapter>>,
+    /// Next address the rescan tries.
+    next_address: u8,
+}
+
+impl<P> I2cDownstream<P> {
+    /// Takes over `bus`, whose data and clock lines are on the GPIOs `sda` and `scl`. Modules
+    /// that do not speak Negicon are driven by the first of `adapters` that detects them.
+    pub(crate) fn new(
+        bus: I2C<pac::I2C0, P, Controller>,
+        sda: u8,
+        scl: u8,
+        adapters: Vec<Box<dyn I2cAdapter>>,
+    ) -> Self {
+        Self {
+            _bus: bus,
+            sda,
+            scl,
+            slots: [None; I2C_SLOTS],
+            adapters,
+            next_address: FIRST_ADDRESS,
+        }
+    }
+
+    fn registers(&self) -> &RegisterBlock {
+        unsafe { &*pac::I2C0::ptr() }
+    }
+
+    /// Tries every address once. Returns the number of modules present.
+    pub(crate) fn scan(&mut self) -> usize {
+        for address in FIRST_ADDRESS..=LAST_ADDRESS {
+            self.probe(address);
+        }
+        self.slots.iter().flatten().count()
+    }
+
+    /// Reads a byte from `address` if no slot holds it yet, and gives the module found there
+    /// the first free slot.
+    fn probe(&mut self, address: u8) {
+        if self.slots.iter().flatten().any(|m| m.address == address) {
+            return;
+        }
+        if self.transfer(address, &[], &mut [0u8; 1]).is_err() {
+            return;
+        }
+        match self.slots.iter_mut().position(|slot| slot.is_none()) {
+            Some(cs) => {
+                let mut adapters = core::mem::take(&mut self.adapters);
+                let adapter = adapters.iter_mut().position(|a| a.detect(self, address));
+                self.adapters = adapters;
+                debug!(
+                    "I2C module at address {:x} in slot {}, adapter {:?}",
+                    address, cs, adapter
+                );
+                self.slots[cs] = Some(Module {
+                    address,
+                    failures: 0,
+                    adapter,
+                });
+            }
+            None => warn!("No I2C slot left for the module at address {:x}", address),
+        }
+    }
+
+    /// Writes `tx` to `address`, then reads `rx` after a repeated start. Gives up if a byte
+    /// takes longer than `BYTE_TIMEOUT_US`.
+    fn transfer(&mut self, address: u8, tx: &[u8], rx: &mut [u8]) -> Result<(), I2cError> {
+        let regs = self.registers();
+        regs.ic_enable.write(|w| w.enable().disabled());
+        regs.ic_tar
+            .write(|w| unsafe { w.ic_tar().bits(address as u16) });
+        regs.ic_enable.write(|w| w.enable().enabled());
+        let result = self.write_bytes(tx, rx.is_empty()).and_then(|_| {
+            let restart = !tx.is_empty();
+            self.read_bytes(rx, restart)
+        });
+        if let Err(I2cError::Timeout) = result {
+            warn!("I2C bus stuck at address {:x}, recovering", address);
+            self.recover();
+        }
+        result
+    }
+
+    fn write_bytes(&self, bytes: &[u8], stop: bool) -> Result<(), I2cError> {
+        let regs = self.registers();
+        for (i, byte) in bytes.iter().enumerate() {
+            let last = i == bytes.len() - 1;
+            regs.ic_data_cmd.write(|w| {
+                if stop && last {
+                    w.stop().enable();
+                } else {
+                    w.stop().disable();
+                }
+                unsafe { w.dat().bits(*byte) }
+            });
+            self.wait(|regs| regs.ic_raw_intr_stat.read().tx_empty().is_active())?;
+            self.check_abort()?;
+        }
+        if stop && !bytes.is_empty() {
+            self.wait_stop()?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&self, bytes: &mut [u8], restart: bool) -> Result<(), I2cError> {
+        let regs = self.registers();
+        let last_index = bytes.len().saturating_sub(1);
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            regs.ic_data_cmd.write(|w| {
+                if restart && i == 0 {
+                    w.restart().enable();
+                } else {
+                    w.restart().disable();
+                }
+                if i == last_index {
+                    w.stop().enable();
+                } else {
+                    w.stop().disable();
+                }
+                w.cmd().read()
+            });
+            self.wait(|regs| {
+                regs.ic_rxflr.read().bits() != 0 || regs.ic_tx_abrt_source.read().bits() != 0
+            })?;
+            self.check_abort()?;
+            *byte = regs.ic_data_cmd.read().dat().bits();
+        }
+        if !bytes.is_empty() {
+            self.wait_stop()?;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `BYTE_TIMEOUT_US` for `done`.
+    fn wait(&self, done: impl Fn(&RegisterBlock) -> bool) -> Result<(), I2cError> {
+        let started = time::now_us();
+        while !done(self.registers()) {
+            if time::now_us().wrapping_sub(started) >= BYTE_TIMEOUT_US {
+                return Err(I2cError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// A module that did not acknowledge makes the controller abort and send a stop.
+    fn check_abort(&self) -> Result<(), I2cError> {
+        let regs = self.registers();
+        if regs.ic_tx_abrt_source.read().bits() == 0 {
+            return Ok(());
+        }
+        // Reading the clear register also clears the abort source
+        regs.ic_clr_tx_abrt.read();
+        self.wait_stop()?;
+        Err(I2cError::Nack)
+    }
+
+    fn wait_stop(&self) -> Result<(), I2cError> {
+        self.wait(|regs| regs.ic_raw_intr_stat.read().stop_det().is_active())?;
+        self.registers().ic_clr_stop_det.read();
+        Ok(())
+    }
+
+    /// Stops the controller and clocks the bus until a module holding the data line low lets
+    /// go of it, then ends with a stop condition and hands the pins back to the controller.
+    fn recover(&mut self) {
+        let regs = self.registers();
+        regs.ic_enable.write(|w| w.enable().disabled());
+        regs.ic_clr_tx_abrt.read();
+        let io = unsafe { &*pac::IO_BANK0::ptr() };
+        let sio = unsafe { &*pac::SIO::ptr() };
+        let (sda, scl) = (1u32 << self.sda, 1u32 << self.scl);
+        // Lines are only ever pulled low, the pull-ups bring them back up
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(sda | scl) });
+        sio.gpio_oe_clr.write(|w| unsafe { w.bits(sda | scl) });
+        for pin in [self.sda, self.scl] {
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .modify(|_, w| w.funcsel().sio());
+        }
+        for _ in 0..9 {
+            if sio.gpio_in.read().bits() & sda != 0 {
+                break;
+            }
+            sio.gpio_oe_set.write(|w| unsafe { w.bits(scl) });
+            pause(RECOVERY_HALF_PERIOD_US);
+            sio.gpio_oe_clr.write(|w| unsafe { w.bits(scl) });
+            pause(RECOVERY_HALF_PERIOD_US);
+        }
+        // Stop condition: data goes high while the clock is high
+        sio.gpio_oe_set.write(|w| unsafe { w.bits(sda) });
+        pause(RECOVERY_HALF_PERIOD_US);
+        sio.gpio_oe_clr.write(|w| unsafe { w.bits(sda) });
+        pause(RECOVERY_HALF_PERIOD_US);
+        for pin in [self.sda, self.scl] {
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .modify(|_, w| w.funcsel().i2c());
+        }
+    }
+}
+
+impl<P> I2cBus for I2cDownstream<P> {
+    fn write_read(&mut self, address: u8, tx: &[u8], rx: &mut [u8]) -> Result<(), I2cError> {
+        self.transfer(address, tx, rx)
+    }
+}
+
+impl<P> I2cDownstream<P> {
+    /// Runs a transaction with a module driven by the adapter at `index`, like one with a
+    /// module that speaks Negicon: the event in `tx` is passed on and `reply` gets the answer.
+    fn adapt(
+        &mut self,
+        index: usize,
+        address: u8,
+        tx: &[u32],
+        reply: &mut [u8],
+    ) -> Result<(), I2cError> {
+        let mut adapters = core::mem::take(&mut self.adapters);
+        let result = exchange(&mut *adapters[index], self, address, tx);
+        self.adapters = adapters;
+        reply[..8].copy_from_slice(&result?.serialize());
+        Ok(())
+    }
+}
+
+/// Hands the event in `tx` to `adapter` and returns the module's answer. Requests for
+/// commands are acknowledged on the module's behalf, a module without news answers with a
+/// ping like a Negicon module does.
+fn exchange(
+    adapter: &mut dyn I2cAdapter,
+    bus: &mut dyn I2cBus,
+    address: u8,
+    tx: &[u32],
+) -> Result<NegiconEvent, I2cError> {
+    let sent = match tx {
+        [first, second, ..] => {
+            NegiconEvent::deserialize(&spi_downstream::packet_of(*first, *second)).ok()
+        }
+        _ => None,
+    };
+    if let Some(sent) = sent.filter(|sent| {
+        matches!(sent.event_type, NegiconEventType::Output) && *sent != spi_downstream::idle_event()
+    }) {
+        if command::is_command(sent.id) {
+            let value = if sent.id == command::DESCRIPTOR {
+                command::NO_DESCRIPTOR
+            } else {
+                command::STATUS_OK
+            };
+            return Ok(NegiconEvent::new(
+                NegiconEventType::Input,
+                sent.id,
+                u7::new(0),
+                value,
+                chain::MODULE_ID,
+                sent.sequence,
+            ));
+        }
+        adapter.output(bus, address, sent.id, sent.value)?;
+    }
+    let idle = NegiconEvent::new(
+        NegiconEventType::Input,
+        0,
+        u7::new(0),
+        0,
+        chain::MODULE_ID,
+        0,
+    );
+    Ok(adapter.input(bus, address)?.unwrap_or(idle))
+}
+
+fn pause(us: u32) {
+    let started = time::now_us();
+    while time::now_us().wrapping_sub(started) < us {}
+}
+
+impl<P> DownstreamInterface for I2cDownstream<P> {
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError> {
+        if tx.is_empty() || tx.len() > MAX_FRAME_WORDS || rx.len() != tx.len() {
+            return Err(DownstreamError::InvalidLength);
+        }
+        let len = tx.len() * 4;
+        let mut tx_bytes = [0u8; MAX_FRAME_WORDS * 4];
+        let mut rx_bytes = [0xffu8; MAX_FRAME_WORDS * 4];
+        for (bytes, word) in tx_bytes.chunks_mut(4).zip(tx) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        // A module that does not acknowledge reads as all ones, like an empty slot on the SPI
+        // bus, so it is detected as absent the same way
+        if let Some(module) = self.slots.get(cs as usize).copied().flatten() {
+            let address = module.address;
+            let result = match module.adapter {
+                Some(index) => self.adapt(index, address, tx, &mut rx_bytes[..len]),
+                None => self.transfer(address, &tx_bytes[..len], &mut rx_bytes[..len]),
+            };
+            if let Some(module) = self.slots[cs as usize].as_mut() {
+                match result {
+                    Ok(()) => module.failures = 0,
+                    Err(e) => {
+                        rx_bytes[..len].fill(0xff);
+                        module.failures = module.failures.saturating_add(1);
+                        if module.failures >= REMOVED_AFTER {
+                            debug!("I2C module at address {:x} removed: {:?}", address, e);
+                            self.slots[cs as usize] = None;
+                        }
+                    }
+                }
+            }
+        }
+        for (word, bytes) in rx.iter_mut().zip(rx_bytes.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Ok(())
+    }
+
+    fn rescan(&mut self) {
+        for _ in 0..RESCAN_ADDRESSES {
+            let address = self.next_address;
+            self.next_address = if address == LAST_ADDRESS {
+                FIRST_ADDRESS
+            } else {
+                address + 1
+            };
+            self.probe(address);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+//! Flash access and the boot state, shared by the firmware and its bootloader.
+#![no_std]
+
+pub mod boot_state;
+pub mod flash;
@@ -5,13 +5,16 @@
 #![no_main]
 
 //extern crate panic_usb_boot;
-use defmt::{debug, info, warn, Debug2Format};
+use defmt::info;
 use defmt_rtt as _;
 
+extern crate alloc;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::pin::pin;
 use embedded_alloc::Heap;
-use embedded_hal::timer::CountDown;
-use fugit::ExtU32;
-use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use embedded_hal::watchdog::WatchdogEnable;
+use fugit::{ExtU32, RateExtU32};
 use panic_probe as _;
 //use panic_usb_boot as _;
 
@@ -25,14 +28,16 @@ use rp2040_hal as hal;
 // use sparkfun_pro_micro_rp2040 as bsp;
 use hal::{
     clocks::{init_clocks_and_plls, Clock},
+    dma::DMAExt,
     entry,
-    gpio::{FunctionPio0, Pins},
+    gpio::{FunctionI2C, FunctionPio0, FunctionSpi, FunctionUart, Interrupt, Pins, PullUp},
     pac,
     pio::PIOExt,
-    rom_data::reset_to_usb_boot,
+    spi::Spi,
+    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
     usb::UsbBus,
     watchdog::Watchdog,
-    Sio, Timer,
+    Sio, Timer, I2C,
 };
 
 use usbd_human_interface_device::{
@@ -40,12 +45,45 @@ use usbd_human_interface_device::{
     usb_class::UsbHidClassBuilder,
 };
 
+mod attention;
+mod chain;
+mod channel;
+mod checksum;
+mod clock_tuner;
+mod command;
+mod dma_scan;
+mod executor;
+mod firmware_update;
+mod gpio_downstream;
+mod hw_spi_downstream;
+mod i2c_downstream;
+mod module_update;
+mod mpr121;
+mod output_queue;
+mod registry;
+mod role;
+mod scheduler;
 mod spi_downstream;
+mod tasks;
+mod time;
+mod uart_downstream;
+mod uart_frame;
 mod upstream;
 
 use crate::{
-    spi_downstream::DownstreamDevice,
-    upstream::{Upstream, UsbUpstream},
+    attention::Attention,
+    chain::Identity,
+    dma_scan::DmaScanner,
+    gpio_downstream::GpioSpiDownstream,
+    hw_spi_downstream::HwSpiDownstream,
+    i2c_downstream::{I2cAdapter, I2cDownstream, I2C_SLOTS},
+    mpr121::Mpr121,
+    registry::{Bus, Registry, SlotMask},
+    role::Role,
+    scheduler::{ScanConfig, ScanScheduler},
+    tasks::{HostEvents, ScanEvents, UpstreamLink},
+    uart_downstream::UartDownstream,
+    upstream::{UartUpstream, Upstream, UsbUpstream},
 };
 
 #[global_allocator]
@@ -74,16 +112,15 @@ const USB_HID_DESCRIPTOR: [u8; 38] = [
     0xc0, //   END_COLLECTION
     0xc0, // END_COLLECTION
 ];
-#[link_section = ".boot2"]
-#[used]
-pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+/// Baud rate of the UART links to remote boards, in both directions.
+const UART_BAUD: u32 = 1_000_000;
 
 #[entry]
 fn main() -> ! {
     info!("Program start");
     {
         use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024 * 64;
+        const HEAP_SIZE: usize = 1024 * 128;
         static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
         unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
     }
@@ -114,7 +151,7 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
@@ -153,11 +190,110 @@ fn main() -> ! {
     pins.gpio28.into_function::<FunctionPio0>();
     pins.gpio29.into_function::<FunctionPio0>();
 
-    let mut downstream_interface = spi_downstream::PioSpiDownstream::new(pio0, sm0, sm1, sm2);
-    let mut tick_timer = timer.count_down();
-    let mut ping_timer = timer.count_down();
-    tick_timer.start(5.millis());
-    ping_timer.start(5.secs());
+    let (mut downstream_interface, mut spi_upstream) =
+        spi_downstream::PioSpiDownstream::new(pio0, sm0, sm1, sm2);
+    let targets = downstream_interface.dma_targets();
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let scanner = DmaScanner::new(dma.ch0, dma.ch1, dma.ch2);
+    let scheduler = ScanScheduler::<{ registry::MAX_SLOTS }>::new(ScanConfig::default());
+    // Data ready line shared by all slots
+    let ready_line = pins.gpio17.into_pull_up_input();
+    ready_line.set_interrupt_enabled(Interrupt::EdgeLow, true);
+    let mut attention = Attention::new();
+    attention.add_line(ready_line.id().num, SlotMask::MAX);
+    time::init(timer.alarm_0().unwrap());
+
+    // Second downstream bus for modules that only speak I2C
+    let sda = pins.gpio4.reconfigure::<FunctionI2C, PullUp>();
+    let scl = pins.gpio5.reconfigure::<FunctionI2C, PullUp>();
+    let (sda_gpio, scl_gpio) = (sda.id().num, scl.id().num);
+    let i2c = I2C::i2c0(
+        pac.I2C0,
+        sda,
+        scl,
+        400.kHz(),
+        &mut pac.RESETS,
+        clocks.system_clock.freq(),
+    );
+    let adapters: Vec<Box<dyn I2cAdapter>> = vec![Box::new(Mpr121::new())];
+    let mut i2c_downstream = I2cDownstream::new(i2c, sda_gpio, scl_gpio, adapters);
+    let i2c_modules = i2c_downstream.scan();
+    info!("{} I2C modules found", i2c_modules);
+
+    // Downstream to a remote board, through an RS-485 transceiver whose driver is enabled by
+    // gpio3
+    let uart_pins = (
+        pins.gpio8.into_function::<FunctionUart>(),
+        pins.gpio9.into_function::<FunctionUart>(),
+    );
+    let uart = UartPeripheral::new(pac.UART1, uart_pins, &mut pac.RESETS)
+        .enable(
+            UartConfig::new(UART_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+            clocks.peripheral_clock.freq(),
+        )
+        .unwrap();
+    let rs485_direction = pins.gpio3.into_push_pull_output();
+    let mut uart_downstream = UartDownstream::new(uart, Some(Box::new(rs485_direction)));
+
+    // A module on the SPI1 peripheral
+    let spi_b_pins = (
+        pins.gpio11.into_function::<FunctionSpi>(),
+        pins.gpio12.reconfigure::<FunctionSpi, PullUp>(),
+        pins.gpio10.into_function::<FunctionSpi>(),
+    );
+    let spi_b = Spi::<_, _, _, 8>::new(pac.SPI1, spi_b_pins).init(
+        &mut pac.RESETS,
+        clocks.peripheral_clock.freq(),
+        1.MHz(),
+        embedded_hal::spi::MODE_1,
+    );
+    let mut spi_b_downstream = HwSpiDownstream::new(
+        spi_b,
+        pins.gpio13.into_push_pull_output(),
+        clocks.peripheral_clock.freq().to_Hz(),
+    );
+
+    // A module on SPI driven from GPIOs
+    let mut gpio_downstream = GpioSpiDownstream::new(
+        pins.gpio6.into_push_pull_output(),
+        pins.gpio7.into_push_pull_output(),
+        pins.gpio14.into_pull_up_input(),
+        pins.gpio15.into_push_pull_output(),
+    );
+
+    // Slots of the PIO bus come first, then every other bus in a fixed order, so the number of a
+    // slot does not depend on what is plugged in
+    let mut registry = Registry::new();
+    registry.attach(Bus::SpiA, &mut downstream_interface);
+    registry.attach(Bus::SpiB, &mut spi_b_downstream);
+    registry.attach(Bus::I2c, &mut i2c_downstream);
+    registry.attach(Bus::Gpio, &mut gpio_downstream);
+    registry.attach(Bus::Uart, &mut uart_downstream);
+    for cs in 0..spi_downstream::CHIP_SELECTS {
+        registry.add(Bus::SpiA, cs as u8);
+    }
+    registry.add(Bus::SpiB, 0);
+    registry.add(Bus::Gpio, 0);
+    if let Some(slot) = registry.add(Bus::Uart, 0) {
+        registry.devices_mut()[slot].set_pipelined(true);
+    }
+    for cs in 0..I2C_SLOTS {
+        registry.add(Bus::I2c, cs as u8);
+    }
+
+    // Upstream for remote boards, through an RS-485 transceiver whose driver is enabled by gpio2
+    let uart_pins = (
+        pins.gpio0.into_function::<FunctionUart>(),
+        pins.gpio1.into_function::<FunctionUart>(),
+    );
+    let uart = UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+        .enable(
+            UartConfig::new(UART_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+            clocks.peripheral_clock.freq(),
+        )
+        .unwrap();
+    let rs485_direction = pins.gpio2.into_push_pull_output();
+    let mut uart_upstream = UartUpstream::new(uart, Some(Box::new(rs485_direction)));
 
     let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x3939))
         .manufacturer("LeekLabs International")
@@ -165,138 +301,60 @@ fn main() -> ! {
         .serial_number("3939")
         .build();
     let mut usb_upstream = UsbUpstream::new(hid, usb_dev);
-    let _i = 0u8;
-
-    let mut downstreams = [
-        DownstreamDevice::new(0),
-        DownstreamDevice::new(1),
-        DownstreamDevice::new(2),
-        DownstreamDevice::new(3),
-        DownstreamDevice::new(4),
-        DownstreamDevice::new(5),
-        DownstreamDevice::new(6),
-        DownstreamDevice::new(7),
-        DownstreamDevice::new(8),
-        DownstreamDevice::new(9),
-        DownstreamDevice::new(10),
-        DownstreamDevice::new(11),
-        DownstreamDevice::new(12),
-        DownstreamDevice::new(13),
-        DownstreamDevice::new(14),
-        DownstreamDevice::new(15),
-        DownstreamDevice::new(16),
-        DownstreamDevice::new(17),
-        DownstreamDevice::new(18),
-        DownstreamDevice::new(19),
-        DownstreamDevice::new(20),
-        DownstreamDevice::new(21),
-        DownstreamDevice::new(22),
-        DownstreamDevice::new(23),
-        DownstreamDevice::new(24),
-        DownstreamDevice::new(25),
-        DownstreamDevice::new(26),
-        DownstreamDevice::new(27),
-        DownstreamDevice::new(28),
-        DownstreamDevice::new(29),
-        DownstreamDevice::new(30),
-        DownstreamDevice::new(31),
+    let mut usb = Upstream::new(&mut usb_upstream);
+    let mut chained = [
+        Upstream::new(&mut spi_upstream),
+        Upstream::new(&mut uart_upstream),
     ];
-    let controller_id = 0u8;
-    let mut upstreams = [
-        Upstream::new(&mut usb_upstream),
-        //Upstream::new(&mut _spi_upstream),
-    ];
-    let mut ping = 0u8;
+    watchdog.pause_on_debug(true);
+    watchdog.start(2_000_000.micros());
+    let role = role::detect(&mut usb, &mut chained, &mut watchdog);
+    info!("Running as {:?} controller", role);
 
-    loop {
-        for up in upstreams.iter_mut() {
-            match up.poll() {
-                Ok(_) => loop {
-                    match up.receive() {
-                        Ok(None) => break,
-                        Ok(Some(e)) => {
-                            match e.event_type {
-                                NegiconEventType::Reboot => {
-                                    debug!("Rebooting to USB boot");
-                                    reset_to_usb_boot(0, 0);
-                                }
-                                _ => {}
-                            };
-                            debug!("Received event from upstream {:?}", Debug2Format(&e))
-                        }
-                        Err(_e) => warn!("Error while receiving event from upstream"),
-                    }
-                },
-                Err(e) => {
-                    warn!("Error while polling upstream: {:?}", e);
-                }
-            }
+    // Chained controllers get theirs from the root
+    let identity = Identity::new();
+    let upstream = match role {
+        Role::Root => {
+            identity.claim_root();
+            usb
         }
+        Role::Chained(index) => chained.into_iter().nth(index).unwrap(),
+    };
 
-        match tick_timer.wait() {
-            Ok(_) => {
-                tick_timer.start(5.millis());
-                for ds in downstreams.iter_mut() {
-                    match ds.poll(&mut delay, &mut downstream_interface) {
-                        Ok(_) => {}
-                        Err(e) => match e {
-                            spi_downstream::DownstreamError::InvalidMessage => {}
-                            _ => {
-                                warn!("Error while polling downstream: {:?}", e);
-                            }
-                        },
-                    }
-                    match ds.receive() {
-                        Ok(None) => {}
-                        Ok(Some(e)) => {
-                            debug!("Received event from downstream {:?}", Debug2Format(&e));
-                            if !e.is_ping() {
-                                for up in upstreams.iter_mut() {
-                                    match up.send(&e) {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            warn!(
-                                                "Error while enqueueing event for upstream: {:?}",
-                                                e
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(_e) => {
-                            //debug!("Error while polling downstream: {:?}", _e);
-                        }
-                    };
-                }
-            }
-            Err(_) => {}
-        }
-        match ping_timer.wait() {
-            Ok(_) => {
-                ping_timer.start(500.millis());
-                let _packet =
-                    &mut NegiconEvent::new(NegiconEventType::Input, 0, ux::u7::new(0), 39, 1, ping)
-                        .serialize();
+    let host_events = HostEvents::new();
+    let scan_events = ScanEvents::new();
+    let links = [UpstreamLink::new()];
 
-                for up in upstreams.iter_mut() {
-                    match up.send(&NegiconEvent::new(
-                        NegiconEventType::Input,
-                        0,
-                        ux::u7::new(0),
-                        39,
-                        controller_id,
-                        ping,
-                    )) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            warn!("Error while sending event to upstream: {:?}", e);
-                        }
-                    }
-                }
-                ping = ping.wrapping_add(1);
-            }
-            Err(_) => {}
-        }
-    }
+    let upstream_task = pin!(tasks::upstream_task(
+        0,
+        upstream,
+        &links[0],
+        &host_events,
+        &identity,
+    ));
+    let command_task = pin!(tasks::command_task(
+        &host_events,
+        &links,
+        &scan_events,
+        &identity
+    ));
+    let heartbeat_task = pin!(tasks::heartbeat_task(
+        &links,
+        &mut watchdog,
+        &identity,
+        role
+    ));
+    let scan_task = pin!(tasks::scan_task(
+        &mut registry,
+        targets,
+        scanner,
+        scheduler,
+        attention,
+        &scan_events,
+        &links,
+        &mut delay,
+        &identity,
+    ));
+
+    executor::run(&mut [upstream_task, command_task, heartbeat_task, scan_task])
 }
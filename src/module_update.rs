@@ -0,0 +1,191 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use defmt::{debug, Format};
+use negicon_protocol::make_u32;
+
+use crate::{
+    checksum::Fletcher16,
+    spi_downstream::{DownstreamDevice, DownstreamError, DownstreamInterface},
+    time,
+};
+
+/// Bootloader commands, sent in the top byte of the first payload word of a frame. The lower
+/// 24 bits hold the argument.
+const BOOT_ENTER: u32 = 0xb0;
+const BOOT_WRITE: u32 = 0xb1;
+const BOOT_FINISH: u32 = 0xb2;
+/// Does nothing, only clocks in the module's answer to the previous frame.
+const BOOT_STATUS: u32 = 0xb3;
+/// Leaves the bootloader for the image already on the module.
+const BOOT_ABORT: u32 = 0xb4;
+
+/// Image bytes sent to the module per frame. The host waits for the progress report of a
+/// block before sending the next, so this also bounds what has to be queued.
+const BLOCK_BYTES: usize = 48;
+/// Largest image that fits the 24 bit offset of `BOOT_WRITE`.
+const MAX_IMAGE_BYTES: u32 = (1 << 24) - 1;
+
+#[derive(Format)]
+pub(crate) enum UpdateError {
+    Downstream(DownstreamError),
+    /// The module does not take frames long enough for image blocks.
+    NotSupported,
+    OutOfSequence,
+    TooLong,
+    /// The checksum given by the host, computed here or computed by the module differ.
+    Checksum,
+}
+
+impl From<DownstreamError> for UpdateError {
+    fn from(e: DownstreamError) -> Self {
+        UpdateError::Downstream(e)
+    }
+}
+
+/// Streams a firmware image from the host into the bootloader of the module in one slot.
+///
+/// The host sends the image three bytes per event, numbered by a 7 bit sequence number.
+/// Bytes are collected into blocks, each written to the module in a single frame.
+pub(crate) struct ModuleUpdate {
+    slot: usize,
+    len: u32,
+    written: u32,
+    seq: u8,
+    block: Vec<u8>,
+    block_bytes: usize,
+    checksum: Fletcher16,
+    /// Time in milliseconds the host last sent part of the image.
+    active_ms: u32,
+}
+
+impl ModuleUpdate {
+    /// Switches the module in `ds` to its bootloader for an image of `len` bytes.
+    pub(crate) fn start(
+        slot: usize,
+        len: u32,
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<Self, UpdateError> {
+        if len == 0 || len > MAX_IMAGE_BYTES {
+            return Err(UpdateError::TooLong);
+        }
+        // Header and command word come on top of the data
+        let block_bytes = ds.frame_words().saturating_sub(2).min(BLOCK_BYTES / 4) * 4;
+        if !ds.is_present() || block_bytes == 0 {
+            return Err(UpdateError::NotSupported);
+        }
+        let mut reply = [0u32; 1];
+        ds.transfer_frame(interface, &[BOOT_ENTER << 24 | len], &mut reply)?;
+        debug!("Slot {} entered bootloader for {} bytes", slot, len);
+        Ok(Self {
+            slot,
+            len,
+            written: 0,
+            seq: 0,
+            block: Vec::with_capacity(block_bytes),
+            block_bytes,
+            checksum: Fletcher16::new(),
+            active_ms: time::now_ms(),
+        })
+    }
+
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Returns true if the host sent nothing for `timeout_ms`.
+    pub(crate) fn is_abandoned(&self, timeout_ms: u32) -> bool {
+        time::now_ms().wrapping_sub(self.active_ms) >= timeout_ms
+    }
+
+    /// Adds the next bytes of the image. Returns the progress in percent whenever a block was
+    /// written to the module, which tells the host to send the next one.
+    pub(crate) fn push(
+        &mut self,
+        seq: u8,
+        bytes: [u8; 3],
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<Option<u8>, UpdateError> {
+        if seq != self.seq {
+            return Err(UpdateError::OutOfSequence);
+        }
+        self.active_ms = time::now_ms();
+        self.seq = (self.seq + 1) & 0x7f;
+        let written = self.written;
+        // The last event may carry padding beyond the image
+        let remaining = self.len - self.written - self.block.len() as u32;
+        for byte in bytes.iter().take(remaining as usize) {
+            self.checksum.add(*byte);
+            self.block.push(*byte);
+            if self.block.len() == self.block_bytes {
+                self.write_block(ds, interface)?;
+            }
+        }
+        if self.written + self.block.len() as u32 == self.len && !self.block.is_empty() {
+            self.write_block(ds, interface)?;
+        }
+        Ok((self.written != written).then(|| self.progress()))
+    }
+
+    /// Completes the update once the host sent its checksum of the image. The module
+    /// reboots into the new image if its own checksum matches.
+    pub(crate) fn finish(
+        &mut self,
+        checksum: u16,
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), UpdateError> {
+        if self.written != self.len || self.checksum.value() != checksum {
+            return Err(UpdateError::Checksum);
+        }
+        let mut reply = [0u32; 1];
+        ds.transfer_frame(
+            interface,
+            &[BOOT_FINISH << 24 | checksum as u32],
+            &mut reply,
+        )?;
+        // The module answers the finish with its checksum in the next frame
+        ds.transfer_frame(interface, &[BOOT_STATUS << 24], &mut reply)?;
+        if reply[0] != BOOT_FINISH << 24 | checksum as u32 {
+            return Err(UpdateError::Checksum);
+        }
+        Ok(())
+    }
+
+    /// Makes the module leave its bootloader and go back to the image it had, for an update
+    /// that failed or was abandoned.
+    pub(crate) fn abort(
+        &mut self,
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), UpdateError> {
+        let mut reply = [0u32; 1];
+        ds.transfer_frame(interface, &[BOOT_ABORT << 24], &mut reply)?;
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), UpdateError> {
+        let mut payload = [0u32; BLOCK_BYTES / 4 + 1];
+        let mut reply = [0u32; BLOCK_BYTES / 4 + 1];
+        payload[0] = BOOT_WRITE << 24 | self.written;
+        self.block.resize(self.block_bytes, 0xff);
+        for (word, bytes) in payload[1..].iter_mut().zip(self.block.chunks(4)) {
+            *word = make_u32(bytes[0], bytes[1], bytes[2], bytes[3]);
+        }
+        let words = self.block_bytes / 4 + 1;
+        ds.transfer_frame(interface, &payload[..words], &mut reply[..words])?;
+        self.written = (self.written + self.block_bytes as u32).min(self.len);
+        self.block.clear();
+        Ok(())
+    }
+
+    fn progress(&self) -> u8 {
+        (self.written as u64 * 100 / self.len as u64) as u8
+    }
+}
@@ -0,0 +1,129 @@
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use ux::u7;
+
+use crate::{
+    chain,
+    i2c_downstream::{I2cAdapter, I2cBus, I2cError},
+};
+
+/// Addresses the chip can be strapped to.
+const FIRST_ADDRESS: u8 = 0x5a;
+const LAST_ADDRESS: u8 = 0x5d;
+const ELECTRODES: u16 = 12;
+
+const TOUCH_STATUS: u8 = 0x00;
+/// First of the touch and release threshold pairs, one pair per electrode.
+const THRESHOLDS: u8 = 0x41;
+/// Electrode configuration, which also switches between stop and run mode.
+const ECR: u8 = 0x5e;
+const CONFIG2: u8 = 0x5d;
+const SOFT_RESET: u8 = 0x80;
+
+/// Value of `CONFIG2` after a reset, used to tell the chip from other modules.
+const CONFIG2_RESET: u8 = 0x24;
+const TOUCH_THRESHOLD: u8 = 12;
+const RELEASE_THRESHOLD: u8 = 6;
+/// Baseline tracking and filter settings of the usual setup, written from `FILTER` on.
+const FILTER: u8 = 0x2b;
+const FILTER_SETTINGS: [u8; 11] = [
+    0x01, 0x01, 0x0e, 0x00, 0x01, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00,
+];
+/// Run mode with all electrodes, with the baseline taken from the first readings.
+const RUN_ALL_ELECTRODES: u8 = 0x8f;
+
+/// Sub id of a touch event, so a release with its value of zero is not taken for a ping.
+const TOUCH: u8 = 1;
+
+/// Adapter for the MPR121 capacitive touch sensor. Each electrode is a control whose id holds
+/// the address of the chip in the high byte and the electrode in the low one. Touching it
+/// sends 1, releasing it 0.
+pub(crate) struct Mpr121 {
+    /// Electrodes last reported as touched, by address.
+    touched: [u16; (LAST_ADDRESS - FIRST_ADDRESS + 1) as usize],
+}
+
+impl Mpr121 {
+    pub(crate) fn new() -> Self {
+        Self {
+            touched: [0; (LAST_ADDRESS - FIRST_ADDRESS + 1) as usize],
+        }
+    }
+}
+
+fn write(bus: &mut dyn I2cBus, address: u8, register: u8, value: u8) -> Result<(), I2cError> {
+    bus.write_read(address, &[register, value], &mut [])
+}
+
+fn read(bus: &mut dyn I2cBus, address: u8, register: u8, rx: &mut [u8]) -> Result<(), I2cError> {
+    bus.write_read(address, &[register], rx)
+}
+
+/// Resets the chip and starts it measuring all electrodes.
+fn start(bus: &mut dyn I2cBus, address: u8) -> Result<(), I2cError> {
+    write(bus, address, SOFT_RESET, 0x63)?;
+    let mut config = [0u8];
+    read(bus, address, CONFIG2, &mut config)?;
+    if config[0] != CONFIG2_RESET {
+        return Err(I2cError::Nack);
+    }
+    for electrode in 0..ELECTRODES as u8 {
+        write(bus, address, THRESHOLDS + 2 * electrode, TOUCH_THRESHOLD)?;
+        write(
+            bus,
+            address,
+            THRESHOLDS + 2 * electrode + 1,
+            RELEASE_THRESHOLD,
+        )?;
+    }
+    for (i, value) in FILTER_SETTINGS.iter().enumerate() {
+        write(bus, address, FILTER + i as u8, *value)?;
+    }
+    write(bus, address, ECR, RUN_ALL_ELECTRODES)
+}
+
+impl I2cAdapter for Mpr121 {
+    fn detect(&mut self, bus: &mut dyn I2cBus, address: u8) -> bool {
+        if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(&address) {
+            return false;
+        }
+        self.touched[(address - FIRST_ADDRESS) as usize] = 0;
+        start(bus, address).is_ok()
+    }
+
+    fn output(
+        &mut self,
+        _bus: &mut dyn I2cBus,
+        _address: u8,
+        _id: u16,
+        _value: i16,
+    ) -> Result<(), I2cError> {
+        // Nothing on the chip to set
+        Ok(())
+    }
+
+    /// Reports one changed electrode per call, the others on the following ones.
+    fn input(
+        &mut self,
+        bus: &mut dyn I2cBus,
+        address: u8,
+    ) -> Result<Option<NegiconEvent>, I2cError> {
+        let mut status = [0u8; 2];
+        read(bus, address, TOUCH_STATUS, &mut status)?;
+        let touched = u16::from_le_bytes(status) & ((1 << ELECTRODES) - 1);
+        let known = &mut self.touched[(address - FIRST_ADDRESS) as usize];
+        let changed = touched ^ *known;
+        if changed == 0 {
+            return Ok(None);
+        }
+        let electrode = changed.trailing_zeros() as u16;
+        *known ^= 1 << electrode;
+        Ok(Some(NegiconEvent::new(
+            NegiconEventType::Input,
+            (address as u16) << 8 | electrode,
+            u7::new(TOUCH),
+            (touched >> electrode & 1) as i16,
+            chain::MODULE_ID,
+            0,
+        )))
+    }
+}
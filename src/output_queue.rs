@@ -0,0 +1,57 @@
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+
+/// Queue of events waiting to be sent to a module.
+///
+/// Outputs for a control that is already queued replace the queued event in place,
+/// so the newest state always reaches the module. The queue only overflows when more
+/// distinct targets are pending than it has room for. Other events keep FIFO semantics.
+pub(crate) struct OutputQueue<const SIZE: usize> {
+    entries: [Option<NegiconEvent>; SIZE],
+    len: usize,
+}
+
+impl<const SIZE: usize> OutputQueue<SIZE> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: [None; SIZE],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: NegiconEvent) -> Result<(), NegiconEvent> {
+        if matches!(event.event_type, NegiconEventType::Output) {
+            for entry in self.entries[..self.len].iter_mut() {
+                match entry {
+                    Some(queued)
+                        if matches!(queued.event_type, NegiconEventType::Output)
+                            && queued.id == event.id =>
+                    {
+                        *queued = event;
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if self.len == SIZE {
+            return Err(event);
+        }
+        self.entries[self.len] = Some(event);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<NegiconEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.entries[0].take();
+        self.entries[..self.len].rotate_left(1);
+        self.len -= 1;
+        event
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
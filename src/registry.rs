@@ -0,0 +1,124 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use defmt::{warn, Format};
+
+use crate::spi_downstream::{DownstreamDevice, DownstreamInterface};
+
+/// Number of slots, bounded by the width of `SlotMask`.
+pub(crate) const MAX_SLOTS: usize = 64;
+
+/// One bit per slot, for the sets of slots the scan and the commands keep track of.
+pub(crate) type SlotMask = u64;
+
+/// A bus modules can be attached to.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Bus {
+    /// The PIO bus with its chip select decoder.
+    SpiA,
+    /// A module on the SPI1 peripheral, see `HwSpiDownstream`.
+    SpiB,
+    I2c,
+    /// A module on SPI driven from GPIOs, see `GpioSpiDownstream`.
+    Gpio,
+    /// A remote controller, see `UartDownstream`.
+    Uart,
+}
+
+impl Bus {
+    /// The DMA scanner drives SPI bus A, all other buses are read by the CPU.
+    pub(crate) fn is_scanned(self) -> bool {
+        self == Bus::SpiA
+    }
+
+    /// Whether the timing of `SpiConfig` applies to the bus, so its slots can be calibrated
+    /// and have their clock tuned.
+    pub(crate) fn is_spi(self) -> bool {
+        matches!(self, Bus::SpiA | Bus::SpiB | Bus::Gpio)
+    }
+
+    /// Scan ticks between two reads of a bus the CPU reads. An I2C transfer takes about half a
+    /// millisecond at 400 kHz, so its modules cannot be read on every tick.
+    pub(crate) fn poll_ticks(self) -> u32 {
+        match self {
+            Bus::I2c => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// The slots of this controller and the bus each of them lives on. Slots are numbered in the
+/// order they were added, which is what the host sees, while the chip select of a slot only
+/// means something on its own bus.
+pub(crate) struct Registry<'a> {
+    devices: Vec<DownstreamDevice>,
+    buses: Vec<Bus>,
+    interfaces: [Option<&'a mut dyn DownstreamInterface>; 5],
+}
+
+impl<'a> Registry<'a> {
+    pub(crate) fn new() -> Self {
+        Self {
+            devices: Vec::with_capacity(MAX_SLOTS),
+            buses: Vec::with_capacity(MAX_SLOTS),
+            interfaces: [None, None, None, None, None],
+        }
+    }
+
+    pub(crate) fn attach(&mut self, bus: Bus, interface: &'a mut dyn DownstreamInterface) {
+        self.interfaces[bus as usize] = Some(interface);
+    }
+
+    /// Adds a slot for chip select `cs` of `bus`. Returns the slot, or None if all are taken.
+    pub(crate) fn add(&mut self, bus: Bus, cs: u8) -> Option<usize> {
+        if self.devices.len() == MAX_SLOTS {
+            warn!("No slot left for chip select {} on {:?}", cs, bus);
+            return None;
+        }
+        let mut device = DownstreamDevice::new(self.devices.len() as u8, cs);
+        if !bus.is_spi() {
+            device.fix_clock();
+        }
+        self.devices.push(device);
+        self.buses.push(bus);
+        Some(self.devices.len() - 1)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub(crate) fn devices(&self) -> &[DownstreamDevice] {
+        &self.devices
+    }
+
+    pub(crate) fn devices_mut(&mut self) -> &mut [DownstreamDevice] {
+        &mut self.devices
+    }
+
+    pub(crate) fn bus(&self, slot: usize) -> Option<Bus> {
+        self.buses.get(slot).copied()
+    }
+
+    /// Lets every attached bus look for new modules, see `DownstreamInterface::rescan`.
+    pub(crate) fn rescan(&mut self) {
+        for interface in self.interfaces.iter_mut().flatten() {
+            interface.rescan();
+        }
+    }
+
+    pub(crate) fn interface(&mut self, bus: Bus) -> Option<&mut (dyn DownstreamInterface + 'a)> {
+        self.interfaces[bus as usize].as_deref_mut()
+    }
+
+    /// The module in `slot` together with the bus to reach it through. None if there is no
+    /// such slot or its bus was never attached.
+    pub(crate) fn slot_mut(
+        &mut self,
+        slot: usize,
+    ) -> Option<(&mut DownstreamDevice, &mut dyn DownstreamInterface)> {
+        let bus = *self.buses.get(slot)?;
+        let interface = self.interfaces[bus as usize].as_deref_mut()?;
+        Some((&mut self.devices[slot], interface))
+    }
+}
@@ -0,0 +1,74 @@
+use defmt::{info, warn, Format};
+use embedded_hal::watchdog::Watchdog as _;
+use rp2040_hal::watchdog::{ScratchRegister, Watchdog};
+
+use crate::{time, upstream::Upstream};
+
+/// How long to wait for a host or an upper controller before running as root anyway, in
+/// milliseconds. The choice is revisited if the upstream stays quiet, see `heartbeat_task`.
+const DETECT_TIMEOUT_MS: u32 = 10_000;
+/// Interval of the status message while waiting, in milliseconds.
+const STATUS_INTERVAL_MS: u32 = 1000;
+/// Counts the detections in a row that found neither, kept across the reset that revisits
+/// the role.
+const FALLBACKS: ScratchRegister = ScratchRegister::Scratch0;
+
+/// Where a board sits in a chain, decided once at boot so every board runs the same image.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Role {
+    /// Talks to the host over USB.
+    Root,
+    /// Chained behind another controller through the upstream with the given index.
+    Chained(usize),
+}
+
+/// Polls all upstreams until the host configures the USB device or another controller starts
+/// talking on one of the chain upstreams, whichever comes first. Falls back to `Root` if
+/// neither happens within `DETECT_TIMEOUT_MS`. Feeds the watchdog while it waits.
+pub(crate) fn detect(
+    usb: &mut Upstream<'_>,
+    chained: &mut [Upstream<'_>],
+    watchdog: &mut Watchdog,
+) -> Role {
+    let started = time::now_ms();
+    let mut status = started;
+    loop {
+        watchdog.feed();
+        if let Err(e) = usb.poll() {
+            warn!("Error while polling USB during role detection: {:?}", e);
+        }
+        if usb.is_connected() {
+            watchdog.write_scratch(FALLBACKS, 0);
+            return Role::Root;
+        }
+        for (index, up) in chained.iter_mut().enumerate() {
+            if let Err(e) = up.poll() {
+                warn!(
+                    "Error while polling upstream {} during role detection: {:?}",
+                    index, e
+                );
+            }
+            if up.is_connected() {
+                watchdog.write_scratch(FALLBACKS, 0);
+                return Role::Chained(index);
+            }
+        }
+        let now = time::now_ms();
+        if now.wrapping_sub(started) >= DETECT_TIMEOUT_MS {
+            warn!("No host or upper controller found, running as root");
+            let fallbacks = watchdog.read_scratch(FALLBACKS);
+            watchdog.write_scratch(FALLBACKS, fallbacks.saturating_add(1));
+            return Role::Root;
+        }
+        if now.wrapping_sub(status) >= STATUS_INTERVAL_MS {
+            info!("Waiting for a host or an upper controller");
+            status = now;
+        }
+    }
+}
+
+/// Returns true if a root without a host may reset to look for one again. Only the first
+/// root that found neither does, so a lone board does not keep rebooting.
+pub(crate) fn may_revisit(watchdog: &Watchdog) -> bool {
+    watchdog.read_scratch(FALLBACKS) <= 1
+}
@@ -0,0 +1,88 @@
+use crate::{registry::SlotMask, spi_downstream::DownstreamDevice};
+
+/// Timing of the downstream scan.
+#[derive(Clone, Copy)]
+pub(crate) struct ScanConfig {
+    /// Length of a scan tick in milliseconds, the resolution of the executor clock.
+    pub(crate) tick_ms: u32,
+    /// Scan ticks between two polls of a present module. The default keeps the 5 ms of the
+    /// fixed scan.
+    pub(crate) present_period: u16,
+    /// Scan ticks between two probes of an empty slot for hotplug.
+    pub(crate) probe_period: u16,
+    /// Scan ticks for which a slot is polled on every tick after it produced input.
+    pub(crate) boost_ticks: u16,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: 1,
+            present_period: 5,
+            probe_period: 50,
+            boost_ticks: 200,
+        }
+    }
+}
+
+/// Decides which slots get polled on each scan tick.
+///
+/// Slots are staggered by their index so that the polls of one period are spread
+/// over its ticks instead of all landing on the same one.
+pub(crate) struct ScanScheduler<const SLOTS: usize> {
+    config: ScanConfig,
+    tick: u32,
+    boost: [u16; SLOTS],
+    /// Slots read when their data ready line is asserted. They are only polled at the probe
+    /// period, in case the line fails.
+    on_demand: SlotMask,
+}
+
+impl<const SLOTS: usize> ScanScheduler<SLOTS> {
+    pub(crate) fn new(config: ScanConfig) -> Self {
+        Self {
+            config,
+            tick: 0,
+            boost: [0; SLOTS],
+            on_demand: 0,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &ScanConfig {
+        &self.config
+    }
+
+    pub(crate) fn set_probe_period(&mut self, ticks: u16) {
+        self.config.probe_period = ticks.max(1);
+    }
+
+    pub(crate) fn set_on_demand(&mut self, slots: SlotMask) {
+        self.on_demand = slots;
+    }
+
+    /// Moves on to the next tick, letting boosts of idle slots run out.
+    pub(crate) fn advance(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+        for boost in self.boost.iter_mut() {
+            *boost = boost.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn should_poll(&self, slot: usize, ds: &DownstreamDevice) -> bool {
+        let period = if self.on_demand & (1 << slot) != 0 {
+            self.config.probe_period.max(1)
+        } else if self.boost[slot] > 0 {
+            1
+        } else if ds.is_present() {
+            self.config.present_period.max(1)
+        } else {
+            self.config.probe_period.max(1)
+        };
+        self.tick.wrapping_add(slot as u32).is_multiple_of(period as u32)
+    }
+
+    /// Records that `slot` produced input, so it is polled on every tick for a while.
+    pub(crate) fn note_activity(&mut self, slot: usize) {
+        self.boost[slot] = self.config.boost_ticks;
+    }
+}
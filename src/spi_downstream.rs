@@ -1,13 +1,25 @@
 extern crate alloc;
 
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
 
-use cortex_m::delay::Delay;
-use defmt::{Format};
-use embedded_hal::{blocking::spi::Transfer};
+use core::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use cortex_m::{delay::Delay, interrupt::Mutex};
+use defmt::{warn, Format};
 use pio::{Label, SideSet};
 use rp2040_hal::{
+    pac::{self, interrupt},
     pio::{
-        Buffers, PIOExt, PinDir, ShiftDirection, StateMachineIndex,
+        Buffers, PIOExt, PinDir, Running, ShiftDirection, StateMachine, StateMachineIndex,
         UninitStateMachine, PIO,
     },
 };
@@ -18,16 +30,158 @@ use negicon_protocol::{
     ringbuf::RingBuffer,
 };
 use ux::u7;
+
+use crate::{
+    clock_tuner::ClockTuner, command, output_queue::OutputQueue, time, upstream::PioSpiUpstream,
+};
 #[derive(Format)]
 pub(crate) enum DownstreamError {
     InvalidMessage,
     UnexpectedReply,
     TxOverflow,
     RxOverflow,
+    InvalidLength,
+}
+
+/// Chip selects of the PIO bus, decoded from five cs pins.
+pub(crate) const CHIP_SELECTS: usize = 32;
+
+/// Largest transfer the downstream bus supports, in 32 bit words.
+pub(crate) const MAX_FRAME_WORDS: usize = 64;
+
+/// Top byte of the header word that starts a variable-length frame. The header also holds
+/// the length of the frame and the longest frame its sender accepts, both in words.
+const FRAME_MAGIC: u32 = 0xa5;
+
+fn frame_header(frame_words: usize, max_words: usize) -> u32 {
+    FRAME_MAGIC << 24 | (frame_words as u32 & 0xff) << 8 | (max_words as u32 & 0xff)
+}
+
+/// Event sent to a module when nothing else is waiting.
+pub(crate) fn idle_event() -> NegiconEvent {
+    NegiconEvent::new(NegiconEventType::Output, 0, u7::new(0), 0x39, 39, 0)
+}
+
+/// Returns the bytes of an event sent as the two words `first` and `second`.
+pub(crate) fn packet_of(first: u32, second: u32) -> [u8; 8] {
+    let mut packet = [0u8; 8];
+    packet[..4].copy_from_slice(&first.to_be_bytes());
+    packet[4..].copy_from_slice(&second.to_be_bytes());
+    packet
+}
+
+/// Returns the longest frame announced in `word`, if it is a frame header.
+fn parse_frame_header(word: u32) -> Option<usize> {
+    if word >> 24 != FRAME_MAGIC {
+        return None;
+    }
+    Some((word & 0xff) as usize)
+}
+
+/// Clock polarity and phase of the downstream bus, numbered like the usual SPI modes.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum SpiMode {
+    Mode0,
+    Mode1,
+    Mode2,
+    Mode3,
+}
+
+impl SpiMode {
+    pub(crate) fn from_value(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SpiMode::Mode0),
+            1 => Some(SpiMode::Mode1),
+            2 => Some(SpiMode::Mode2),
+            3 => Some(SpiMode::Mode3),
+            _ => None,
+        }
+    }
+
+    /// SCK idles high.
+    pub(crate) fn cpol(self) -> bool {
+        matches!(self, SpiMode::Mode2 | SpiMode::Mode3)
+    }
+
+    /// Data is sampled on the trailing clock edge.
+    pub(crate) fn cpha(self) -> bool {
+        matches!(self, SpiMode::Mode1 | SpiMode::Mode3)
+    }
+}
+
+/// Largest number of extra state machine cycles before MISO is sampled, limited by the
+/// delay bits left next to the side-set bit.
+pub(crate) const MAX_SAMPLE_DELAY: u8 = 7;
+
+/// Bus timing used for one slot.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct SpiConfig {
+    /// Divisor of the system clock for the data state machine. A bit takes two of its cycles.
+    pub(crate) clock_divisor: u16,
+    pub(crate) mode: SpiMode,
+    /// Extra cycles between shifting out a bit and sampling MISO, giving a slow reply time
+    /// to arrive over long cables.
+    pub(crate) sample_delay: u8,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            clock_divisor: 84,
+            mode: SpiMode::Mode1,
+            sample_delay: 0,
+        }
+    }
 }
 
 pub trait DownstreamInterface {
-    fn transfer(&mut self, cs: u8, packet: &mut [u8; 8]) -> Result<[u8; 8], DownstreamError>;
+    /// Switches the bus to `config` for the following transfers. Interfaces without
+    /// adjustable timing ignore it.
+    fn configure(&mut self, _config: SpiConfig) {}
+
+    /// Returns false while a transfer with the module on `cs` would have to wait, such as for
+    /// the answer to the previous one on a half duplex link.
+    fn is_ready(&mut self, _cs: u8) -> bool {
+        true
+    }
+
+    /// Looks for modules plugged in since the last call, on buses that find their modules by
+    /// address. Called at the probe period.
+    fn rescan(&mut self) {}
+
+    /// Clocks out `tx` to the module on `cs` while reading the same number of words into `rx`.
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError>;
+
+    /// Starts clocking out `tx` to the module on `cs` without waiting for it to finish, for
+    /// interfaces that signal the end of a transfer. Returns false if the transfer has to go
+    /// through `transfer_words` instead.
+    fn start_words(&mut self, _cs: u8, _tx: &[u32]) -> Result<bool, DownstreamError> {
+        Ok(false)
+    }
+
+    /// Reads the reply of the transfer started by `start_words` into `rx` once it is done.
+    fn poll_words(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _rx: &mut [u32],
+    ) -> Poll<Result<(), DownstreamError>> {
+        Poll::Ready(Err(DownstreamError::InvalidLength))
+    }
+
+    fn transfer(&mut self, cs: u8, packet: &mut [u8; 8]) -> Result<[u8; 8], DownstreamError> {
+        let tx = [
+            make_u32(packet[0], packet[1], packet[2], packet[3]),
+            make_u32(packet[4], packet[5], packet[6], packet[7]),
+        ];
+        let mut rx = [0u32; 2];
+        self.transfer_words(cs, &tx, &mut rx)?;
+        Ok(packet_of(rx[0], rx[1]))
+    }
 }
 
 pub(crate) struct PioSpiDownstream<
@@ -40,19 +194,26 @@ pub(crate) struct PioSpiDownstream<
     cs_tx: rp2040_hal::pio::Tx<(P, SM0)>,
     data_tx: rp2040_hal::pio::Tx<(P, SM1)>,
     data_rx: rp2040_hal::pio::Rx<(P, SM1)>,
-    pub(crate) slave_tx: rp2040_hal::pio::Tx<(P, SM2)>,
-    pub(crate) slave_rx: rp2040_hal::pio::Rx<(P, SM2)>,
+    data_sm: StateMachine<(P, SM1), Running>,
+    /// Where the data program was installed, and the code currently loaded there.
+    data_offset: u8,
+    data_code: Vec<u16>,
+    sck_pin: u8,
+    config: SpiConfig,
+    _slave: PhantomData<SM2>,
 }
 
 impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachineIndex>
     PioSpiDownstream<P, SM0, SM1, SM2>
 {
+    /// Sets up the downstream master on `sm0` and `sm1` and the upstream slave on `sm2`,
+    /// which is handed out as a `PioSpiUpstream`.
     pub fn new(
         mut pio: PIO<P>,
         sm0: UninitStateMachine<(P, SM0)>,
         sm1: UninitStateMachine<(P, SM1)>,
         sm2: UninitStateMachine<(P, SM2)>,
-    ) -> Self {
+    ) -> (Self, PioSpiUpstream<P, SM2>) {
         let sck_pin_id = 18;
 
         let miso_pin_id = 20;
@@ -64,11 +225,15 @@ impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachin
         let slave_mosi = 28;
         let slave_miso = 27;
 
-        let (program, wrap_source, wrap_target) = spi_program();
+        let config = SpiConfig::default();
+        let (program, wrap_source, wrap_target) =
+            spi_program(config.mode.cpha(), config.sample_delay);
 
         let mut program = program.assemble_with_wrap(wrap_source, wrap_target);
         program.side_set = SideSet::new(true, 1, false);
+        let data_code = program.code.iter().copied().collect::<Vec<u16>>();
         let program = pio.install(&program).unwrap();
+        let data_offset = program.offset();
 
         let (mut sm, data_rx, data_tx) = rp2040_hal::pio::PIOBuilder::from_program(program)
             .buffers(Buffers::RxTx)
@@ -76,7 +241,7 @@ impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachin
             .out_pins(mosi_pin_id, 1)
             .out_shift_direction(ShiftDirection::Left)
             .side_set_pin_base(sck_pin_id)
-            .clock_divisor_fixed_point(84, 0)
+            .clock_divisor_fixed_point(config.clock_divisor, 0)
             /*  .autopull(true)
             .pull_threshold(32)*/
             .build(sm1);
@@ -85,7 +250,8 @@ impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachin
             (sck_pin_id, PinDir::Output),
             (miso_pin_id, PinDir::Input),
         ]);
-        sm.start();
+        let data_sm = sm.start();
+        set_sck_inverted(sck_pin_id, config.mode.cpol());
 
         let (program, wrap_source, wrap_target) = cs_program();
         let program = program.assemble_with_wrap(wrap_source, wrap_target);
@@ -128,102 +294,917 @@ impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachin
 
         sm.start();
 
-        Self {
-            pio,
-            cs_tx,
-            data_tx,
-            data_rx,
-            slave_tx,
-            slave_rx,
+        // The data state machine raises flag 0 at the end of each transfer
+        pio.irq0().enable_sm_interrupt(0);
+        let transfer_irq = if P::id() == 0 {
+            pac::Interrupt::PIO0_IRQ_0
+        } else {
+            pac::Interrupt::PIO1_IRQ_0
+        };
+        unsafe {
+            pac::NVIC::unmask(transfer_irq);
         }
+
+        (
+            Self {
+                pio,
+                cs_tx,
+                data_tx,
+                data_rx,
+                data_sm,
+                data_offset,
+                data_code,
+                sck_pin: sck_pin_id,
+                config,
+                _slave: PhantomData,
+            },
+            PioSpiUpstream::new(slave_tx, slave_rx),
+        )
     }
 }
 
+impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachineIndex>
+    PioSpiDownstream<P, SM0, SM1, SM2>
+{
+    /// FIFO addresses and DREQs of the cs and data state machines, for feeding them by DMA.
+    pub(crate) fn dma_targets(&self) -> DmaTargets {
+        DmaTargets {
+            pio: P::id(),
+            cs_tx: (self.cs_tx.fifo_address() as u32, self.cs_tx.dreq_value()),
+            data_tx: (self.data_tx.fifo_address() as u32, self.data_tx.dreq_value()),
+            data_rx: (self.data_rx.fifo_address() as u32, self.data_rx.dreq_value()),
+        }
+    }
+}
+
+impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachineIndex>
+    PioSpiDownstream<P, SM0, SM1, SM2>
+{
+    /// Starts a transfer that fits into the TX FIFO without waiting for it to finish.
+    /// Completion is signalled by `TransferDone`, the reply is collected with `finish_transfer`.
+    pub(crate) fn start_transfer(&mut self, cs: u8, tx: &[u32]) -> Result<(), DownstreamError> {
+        if tx.is_empty() || tx.len() >= FIFO_WORDS {
+            return Err(DownstreamError::InvalidLength);
+        }
+        TRANSFER_DONE[P::id()].store(false, Ordering::Release);
+        // A DMA scan may still be draining the FIFOs
+        while !self.cs_tx.write_u8_replicated(cs) {}
+        while !self.data_tx.write(tx.len() as u32 - 1) {}
+        for word in tx {
+            while !self.data_tx.write(*word) {}
+        }
+        Ok(())
+    }
+
+    /// Resolves once the transfer started by `start_transfer` is complete.
+    pub(crate) fn transfer_done(&self) -> TransferDone {
+        TransferDone { pio: P::id() }
+    }
+
+    /// Blocks until the data state machine waits for the next transfer, so its program and
+    /// clock can be changed safely. A DMA scan may still be clocking out its last bits.
+    fn wait_idle(&self) {
+        while !self.data_tx.is_empty()
+            || self.data_sm.instruction_address() != self.data_offset as u32
+        {}
+    }
+
+    /// Rewrites the instructions of the data program that differ for the given phase and
+    /// sample delay. Only side-set and delay bits change, so no jump needs relocating.
+    fn load_data_program(&mut self, cpha: bool, sample_delay: u8) {
+        let pio = pio_registers::<P>();
+        let (program, wrap_source, wrap_target) = spi_program(cpha, sample_delay);
+        let program = program.assemble_with_wrap(wrap_source, wrap_target);
+        for (i, (old, new)) in self.data_code.iter_mut().zip(program.code.iter()).enumerate() {
+            if old != new {
+                pio.instr_mem[self.data_offset as usize + i]
+                    .write(|w| unsafe { w.bits(*new as u32) });
+                *old = *new;
+            }
+        }
+    }
+
+    /// Reads the reply of a transfer started with `start_transfer`.
+    pub(crate) fn finish_transfer(&mut self, rx: &mut [u32]) -> nb::Result<(), DownstreamError> {
+        if !TRANSFER_DONE[P::id()].load(Ordering::Acquire) {
+            return Err(nb::Error::WouldBlock);
+        }
+        for word in rx.iter_mut() {
+            *word = self.data_rx.read().ok_or(nb::Error::Other(DownstreamError::RxOverflow))?;
+        }
+        Ok(())
+    }
+}
+
+/// Depth of the state machine FIFOs in words.
+const FIFO_WORDS: usize = 4;
+
+/// End of the last transfer started on each PIO block.
+static TRANSFER_DONE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static TRANSFER_WAKERS: Mutex<RefCell<[Option<Waker>; 2]>> =
+    Mutex::new(RefCell::new([None, None]));
+
+/// Resolves once the transfer started by `PioSpiDownstream::start_transfer` is complete.
+pub(crate) struct TransferDone {
+    pio: usize,
+}
+
+impl Future for TransferDone {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let done = &TRANSFER_DONE[self.pio];
+        if done.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        cortex_m::interrupt::free(|cs| {
+            TRANSFER_WAKERS.borrow(cs).borrow_mut()[self.pio] = Some(cx.waker().clone());
+        });
+        // The interrupt may have fired before the waker was registered
+        if done.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Sleeps until the current transfer on PIO block `pio` is complete.
+fn wait_for_transfer(pio: usize) {
+    let done = &TRANSFER_DONE[pio];
+    while !done.load(Ordering::Acquire) {
+        // Checking with interrupts masked makes sure the wakeup is not missed, WFI still
+        // returns for the pending interrupt
+        cortex_m::interrupt::free(|_| {
+            if !done.load(Ordering::Acquire) {
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+}
+
+/// Clears the end-of-transfer flag of PIO block `pio`, marks its transfer as done and wakes
+/// the task waiting for it.
+fn transfer_interrupt(pio: usize) {
+    pio_block(pio).irq.write(|w| unsafe { w.bits(1) });
+    TRANSFER_DONE[pio].store(true, Ordering::Release);
+    cortex_m::interrupt::free(|cs| {
+        if let Some(waker) = TRANSFER_WAKERS.borrow(cs).borrow_mut()[pio].take() {
+            waker.wake();
+        }
+    });
+}
+
+#[interrupt]
+fn PIO0_IRQ_0() {
+    transfer_interrupt(0);
+}
+
+#[interrupt]
+fn PIO1_IRQ_0() {
+    transfer_interrupt(1);
+}
+
+/// Masks or unmasks the end-of-transfer interrupt of PIO block `pio`. A DMA scan masks it,
+/// as the data state machine raises it for every slot. Unmasking clears the flag the scan left.
+pub(crate) fn set_transfer_irq(pio: usize, enabled: bool) {
+    let regs = pio_block(pio);
+    if enabled {
+        regs.irq.write(|w| unsafe { w.bits(1) });
+    }
+    regs.sm_irq[0].irq_inte.modify(|_, w| w.sm0().bit(enabled));
+}
+
+fn pio_block(id: usize) -> &'static pac::pio0::RegisterBlock {
+    if id == 0 {
+        unsafe { &*pac::PIO0::ptr() }
+    } else {
+        unsafe { &*pac::PIO1::ptr() }
+    }
+}
+
+fn pio_registers<P: PIOExt>() -> &'static pac::pio0::RegisterBlock {
+    pio_block(P::id())
+}
+
+/// Inverts the SCK pad so the side-set low level drives the line high, which gives CPOL.
+fn set_sck_inverted(pin: u8, inverted: bool) {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    io.gpio[pin as usize].gpio_ctrl.modify(|_, w| {
+        if inverted {
+            w.outover().invert()
+        } else {
+            w.outover().normal()
+        }
+    });
+}
+
+/// `(fifo address, dreq)` of each state machine FIFO taking part in a transfer.
+pub(crate) struct DmaTargets {
+    /// Index of the PIO block the state machines belong to.
+    pub(crate) pio: usize,
+    pub(crate) cs_tx: (u32, u8),
+    pub(crate) data_tx: (u32, u8),
+    pub(crate) data_rx: (u32, u8),
+}
+
 impl<P: PIOExt, SM0: StateMachineIndex, SM1: StateMachineIndex, SM2: StateMachineIndex>
     DownstreamInterface for PioSpiDownstream<P, SM0, SM1, SM2>
 {
-    fn transfer(&mut self, cs: u8, packet: &mut [u8; 8]) -> Result<[u8; 8], DownstreamError> {
-        let first = make_u32(packet[0], packet[1], packet[2], packet[3]);
-        let second = make_u32(packet[4], packet[5], packet[6], packet[7]);
+    fn configure(&mut self, config: SpiConfig) {
+        if config == self.config {
+            return;
+        }
+        self.wait_idle();
+        if config.clock_divisor != self.config.clock_divisor {
+            self.data_sm.clock_divisor_fixed_point(config.clock_divisor, 0);
+        }
+        if config.mode.cpha() != self.config.mode.cpha()
+            || config.sample_delay != self.config.sample_delay
+        {
+            self.load_data_program(config.mode.cpha(), config.sample_delay);
+        }
+        if config.mode.cpol() != self.config.mode.cpol() {
+            set_sck_inverted(self.sck_pin, config.mode.cpol());
+        }
+        self.config = config;
+    }
+
+    fn start_words(&mut self, cs: u8, tx: &[u32]) -> Result<bool, DownstreamError> {
+        if tx.len() >= FIFO_WORDS {
+            return Ok(false);
+        }
+        self.start_transfer(cs, tx)?;
+        Ok(true)
+    }
+
+    fn poll_words(
+        &mut self,
+        cx: &mut Context<'_>,
+        rx: &mut [u32],
+    ) -> Poll<Result<(), DownstreamError>> {
+        if Pin::new(&mut self.transfer_done()).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Poll::Ready(nb::block!(self.finish_transfer(rx)))
+    }
+
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError> {
+        if tx.is_empty() || tx.len() > MAX_FRAME_WORDS || rx.len() != tx.len() {
+            return Err(DownstreamError::InvalidLength);
+        }
+        if tx.len() < FIFO_WORDS {
+            // Short frames fit into the FIFO, so the CPU can sleep until the PIO is done
+            self.start_transfer(cs, tx)?;
+            wait_for_transfer(P::id());
+            return nb::block!(self.finish_transfer(rx));
+        }
         self.cs_tx.write_u8_replicated(cs);
-        self.data_tx.write(first);
-        self.data_tx.write(second);
-        let mut res = [0u8; 8];
-        loop {
-            match self.data_rx.read() {
-                Some(word) => {
-                    res[0] = (word >> 24) as u8;
-                    res[1] = (word >> 16) as u8;
-                    res[2] = (word >> 8) as u8;
-                    res[3] = word as u8;
-                    break;
-                }
-                None => {}
+        // The data state machine takes the number of words to clock minus one first
+        while !self.data_tx.write(tx.len() as u32 - 1) {}
+        let mut sent = 0;
+        let mut received = 0;
+        while received < rx.len() {
+            if sent < tx.len() && self.data_tx.write(tx[sent]) {
+                sent += 1;
             }
-        }
-        loop {
             match self.data_rx.read() {
                 Some(word) => {
-                    res[4] = (word >> 24) as u8;
-                    res[5] = (word >> 16) as u8;
-                    res[6] = (word >> 8) as u8;
-                    res[7] = word as u8;
-                    break;
+                    rx[received] = word;
+                    received += 1;
                 }
                 None => {}
             }
         }
-        Ok(res)
+        Ok(())
     }
 }
 
+/// Events a frame carries at most, as many as the receive queue holds until the scan takes
+/// them.
+const FRAME_EVENTS: usize = 4;
+
+/// Header transfers per sample delay during calibration, all of which have to be answered.
+const CALIBRATION_ROUNDS: usize = 8;
+
+/// Number of consecutive invalid replies after which the clock of a slot is slowed down, or the
+/// slot is considered empty if it already is at the slowest.
+const ABSENT_THRESHOLD: u8 = 3;
+
+/// Transfers a newly detected module gets to answer the descriptor request before it is
+/// taken for one without a descriptor.
+const IDENTIFY_TRANSFERS: u8 = 8;
+
+/// Minimum time in microseconds between two out-of-band output transfers to the same slot,
+/// so a slot with a steady stream of outputs cannot starve the others.
+const OUTPUT_MIN_INTERVAL_US: u32 = 1000;
+
 pub(crate) struct DownstreamDevice {
+    /// Index in the registry, which is what the host knows the slot by.
+    slot: u8,
+    /// Chip select on the bus of the slot.
     cs: u8,
-    tx_buffer: RingBuffer<NegiconEvent, 4>,
+    tx_buffer: OutputQueue<16>,
     rx_buffer: RingBuffer<NegiconEvent, 4>,
+    present: bool,
+    missed: u8,
+    /// Latest answer to each command and the timer value in microseconds when it arrived, so
+    /// queries running at the same time do not overwrite each other's answers.
+    acks: BTreeMap<u16, (NegiconEvent, u32)>,
+    /// Invalid replies while present, since the last `take_failed`.
+    failed: u32,
+    /// Ids of the controls this module has reported, used to route host outputs to it.
+    ids: BTreeSet<u16>,
+    /// Controls the host routed to this slot with `MAP_OUTPUT`.
+    mapped: BTreeSet<u16>,
+    /// Last output sent to each control, replayed when the module comes back after a hot-swap.
+    outputs: BTreeMap<u16, NegiconEvent>,
+    /// Descriptor of the module `ids` and `outputs` belong to, None until one was identified.
+    descriptor: Option<i16>,
+    /// Transfers since the descriptor request of a newly detected module was queued.
+    identifying: Option<u8>,
+    replay: Vec<NegiconEvent>,
+    last_output_us: u32,
+    requests: RingBuffer<NegiconEvent, 4>,
+    responses: RingBuffer<NegiconEvent, 4>,
+    pipelined: bool,
+    in_flight: Option<NegiconEvent>,
+    /// Request whose reply did not match, sent again before any other. Given up on if the
+    /// second reply does not match either, which the caller sees as a timeout.
+    resend: Option<NegiconEvent>,
+    resent: Option<NegiconEvent>,
+    /// Longest frame the module accepts, in words including the header. Modules that did not
+    /// answer the negotiation only get single events.
+    frame_words: usize,
+    negotiate: bool,
+    /// Event of the transaction in progress and whether it expects a response.
+    sent: Option<(NegiconEvent, bool)>,
+    /// Controllers chained behind this slot, learned from the events they sent.
+    controllers: BTreeSet<u8>,
+    /// Controllers we passed host events to. Their command reports are sent upstream.
+    forwarded: BTreeSet<u8>,
+    /// Host events for chained controllers. Unlike outputs they are never merged.
+    passthrough: RingBuffer<NegiconEvent, 16>,
+    /// Timer value in microseconds until which the controller behind this slot is busy.
+    paused_until_us: Option<u32>,
+    clock: ClockTuner,
 }
 
 impl DownstreamDevice {
-    pub(crate) fn new(cs: u8) -> Self {
+    pub(crate) fn new(slot: u8, cs: u8) -> Self {
         Self {
+            slot,
             cs,
-            tx_buffer: RingBuffer::new(),
+            tx_buffer: OutputQueue::new(),
             rx_buffer: RingBuffer::new(),
+            present: false,
+            missed: 0,
+            acks: BTreeMap::new(),
+            failed: 0,
+            ids: BTreeSet::new(),
+            mapped: BTreeSet::new(),
+            outputs: BTreeMap::new(),
+            descriptor: None,
+            identifying: None,
+            replay: Vec::new(),
+            last_output_us: 0,
+            requests: RingBuffer::new(),
+            responses: RingBuffer::new(),
+            pipelined: false,
+            in_flight: None,
+            resend: None,
+            resent: None,
+            frame_words: 2,
+            negotiate: false,
+            sent: None,
+            controllers: BTreeSet::new(),
+            forwarded: BTreeSet::new(),
+            passthrough: RingBuffer::new(),
+            paused_until_us: None,
+            clock: ClockTuner::new(SpiConfig::default()),
         }
     }
 
-    pub fn poll(
+    /// Runs one transaction with the module. Other tasks run while the bus is busy if the
+    /// interface signals the end of a transfer.
+    pub async fn poll(
         &mut self,
         _delay: &mut Delay,
         interface: &mut dyn DownstreamInterface,
     ) -> Result<(), DownstreamError> {
-        let event = self.tx_buffer.pop().unwrap_or(NegiconEvent::new(
-            NegiconEventType::Output,
-            0,
-            u7::new(0),
-            0x39,
-            39,
-            0,
-        ));
+        self.negotiate_if_needed(interface)?;
+        if self.frame_words > 2 {
+            return self.poll_frame(interface);
+        }
+        let mut packet = self.next_packet();
+        interface.configure(self.clock.config());
+        let tx = [
+            make_u32(packet[0], packet[1], packet[2], packet[3]),
+            make_u32(packet[4], packet[5], packet[6], packet[7]),
+        ];
+        if !interface.start_words(self.cs, &tx)? {
+            let reply = interface.transfer(self.cs, &mut packet)?;
+            return self.complete(&reply);
+        }
+        let mut rx = [0u32; 2];
+        poll_fn(|cx| interface.poll_words(cx, &mut rx)).await?;
+        self.complete(&packet_of(rx[0], rx[1]))
+    }
 
-        let mut packet = event.serialize();
-        let reply = interface.transfer(self.cs, &mut packet)?;
-        let deserialized = NegiconEvent::deserialize(&reply);
+    /// Exchanges a frame with a module that negotiated frames. It carries an event for each
+    /// one waiting to go out, at least one and at most as many as fit. Every event of the frame
+    /// is handled like a transaction of its own and the module answers each with one of its
+    /// own. A reply without a header comes from a module that lost the negotiation, such as a
+    /// legacy one plugged in instead, and its first two words are taken as its event.
+    fn poll_frame(
+        &mut self,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), DownstreamError> {
+        let mut tx = [0u32; 1 + 2 * FRAME_EVENTS];
+        let mut rx = [0u32; 1 + 2 * FRAME_EVENTS];
+        let mut requests = [None; FRAME_EVENTS];
+        let capacity = ((self.frame_words - 1) / 2).min(FRAME_EVENTS);
+        let mut events = 0;
+        while events == 0 || (events < capacity && self.has_queued()) {
+            let (event, is_request) = self.next_event();
+            let packet = event.serialize();
+            tx[1 + 2 * events] = make_u32(packet[0], packet[1], packet[2], packet[3]);
+            tx[2 + 2 * events] = make_u32(packet[4], packet[5], packet[6], packet[7]);
+            requests[events] = is_request.then_some(event);
+            events += 1;
+        }
+        let len = 1 + 2 * events;
+        tx[0] = frame_header(len, MAX_FRAME_WORDS);
+        interface.configure(self.clock.config());
+        interface.transfer_words(self.cs, &tx[..len], &mut rx[..len])?;
+        if parse_frame_header(rx[0]).is_none() {
+            self.frame_words = 2;
+            self.negotiate = true;
+            return self.handle_reply(requests[0], &packet_of(rx[0], rx[1]));
+        }
+        let mut result = Ok(());
+        for (i, request) in requests[..events].iter().enumerate() {
+            let reply = packet_of(rx[1 + 2 * i], rx[2 + 2 * i]);
+            if let Err(e) = self.handle_reply(*request, &reply) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Returns true if an event is waiting to go out to the module.
+    fn has_queued(&mut self) -> bool {
+        !self.replay.is_empty()
+            || self.resend.is_some()
+            || self.requests.peek().is_some()
+            || self.passthrough.peek().is_some()
+            || !self.tx_buffer.is_empty()
+    }
+
+    /// Runs the frame length negotiation if the module was just detected.
+    pub fn negotiate_if_needed(
+        &mut self,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), DownstreamError> {
+        if self.negotiate {
+            self.negotiate = false;
+            self.negotiate_frame_len(interface)?;
+        }
+        Ok(())
+    }
+
+    /// Picks the event for the next transaction. Its reply has to be passed to `complete`.
+    pub fn next_packet(&mut self) -> [u8; 8] {
+        let (event, is_request) = self.next_event();
+        self.sent = Some((event, is_request));
+        event.serialize()
+    }
+
+    /// Takes the event to send next and whether it expects a response. An idle event if
+    /// nothing is waiting.
+    fn next_event(&mut self) -> (NegiconEvent, bool) {
+        match self.replay.pop() {
+            Some(event) => (event, false),
+            None => match self.resend.take().or_else(|| self.requests.pop()) {
+                Some(event) => (event, true),
+                None => (
+                    self.passthrough
+                        .pop()
+                        .or_else(|| self.tx_buffer.pop())
+                        .unwrap_or(idle_event()),
+                    false,
+                ),
+            },
+        }
+    }
+
+    /// Handles the module's reply to the packet returned by the last `next_packet`.
+    pub fn complete(&mut self, reply: &[u8; 8]) -> Result<(), DownstreamError> {
+        let request = match self.sent.take() {
+            Some((event, true)) => Some(event),
+            _ => None,
+        };
+        self.handle_reply(request, reply)
+    }
+
+    /// Handles the module's reply to a transaction that carried `request`, if it carried one.
+    fn handle_reply(
+        &mut self,
+        request: Option<NegiconEvent>,
+        reply: &[u8; 8],
+    ) -> Result<(), DownstreamError> {
+        if let Some(transfers) = self.identifying.as_mut() {
+            *transfers += 1;
+            if *transfers > IDENTIFY_TRANSFERS {
+                self.identified(command::NO_DESCRIPTOR);
+            }
+        }
+        // A pipelined module answers the request it got in the previous transaction
+        let answered = if self.pipelined {
+            core::mem::replace(&mut self.in_flight, request)
+        } else {
+            request
+        };
+        let deserialized = NegiconEvent::deserialize(reply);
         match deserialized {
             Ok(event) => {
-                self.rx_buffer.push(event);
-                Ok(())
+                if !self.present {
+                    self.present = true;
+                    self.negotiate = true;
+                    self.clock.found();
+                    self.identify();
+                }
+                self.missed = 0;
+                self.clock.record(true);
+                match answered {
+                    Some(request) if request.id == event.id => {
+                        if self.resent == Some(request) {
+                            self.resent = None;
+                        }
+                        self.accept_response(event);
+                        Ok(())
+                    }
+                    Some(request) if self.pipelined => {
+                        self.accept(event);
+                        if self.resent == Some(request) {
+                            warn!("Slot {} did not answer request {:x}", self.slot, request.id);
+                            self.resent = None;
+                        } else {
+                            self.resend = Some(request);
+                            self.resent = Some(request);
+                        }
+                        Err(DownstreamError::UnexpectedReply)
+                    }
+                    _ => {
+                        self.accept(event);
+                        Ok(())
+                    }
+                }
+            }
+            Err(_e) => {
+                self.missed = self.missed.saturating_add(1);
+                if !self.present {
+                    self.clock.next_probe();
+                    return Err(DownstreamError::InvalidMessage);
+                }
+                self.failed = self.failed.saturating_add(1);
+                if self.missed < ABSENT_THRESHOLD {
+                    self.clock.record(false);
+                } else if self.clock.step_down() {
+                    // A module that stopped answering may only need a slower clock
+                    self.missed = 0;
+                } else {
+                    // Whatever is plugged in next may be chained differently
+                    self.present = false;
+                    self.controllers.clear();
+                    self.forwarded.clear();
+                }
+                Err(DownstreamError::InvalidMessage)
+            }
+        }
+    }
+
+    pub fn cs(&self) -> u8 {
+        self.cs
+    }
+
+    fn accept(&mut self, event: NegiconEvent) {
+        if command::is_command(event.id) {
+            if event.id == command::BUSY {
+                let pause_us = event.value.max(0) as u32 * 1000;
+                self.paused_until_us = Some(time::now_us().wrapping_add(pause_us));
+            } else if command::is_chain_report(event.id) {
+                // Also sent by controllers further down than the ones we forwarded to
+                self.controllers.insert(event.controller_id);
+                self.queue_received(event);
+            } else if self.forwarded.contains(&event.controller_id)
+                && !command::is_neighbour_request(event.id)
+            {
+                // A chained controller reporting on a command from the host
+                self.queue_received(event);
+            } else {
+                self.set_ack(event);
+            }
+            return;
+        }
+        self.controllers.insert(event.controller_id);
+        if !event.is_ping() {
+            self.ids.insert(event.id);
+        }
+        self.queue_received(event);
+    }
+
+    fn queue_received(&mut self, event: NegiconEvent) {
+        if self.rx_buffer.push(event).is_err() {
+            warn!("Dropping event from slot {}, receive queue full", self.slot);
+        }
+    }
+
+    fn accept_response(&mut self, event: NegiconEvent) {
+        if command::is_command(event.id) {
+            self.set_ack(event);
+            return;
+        }
+        if self.responses.push(event).is_err() {
+            warn!("Dropping response from slot {}", self.slot);
+        }
+    }
+
+    fn set_ack(&mut self, event: NegiconEvent) {
+        if event.id == command::DESCRIPTOR && self.identifying.is_some() {
+            self.identified(event.value);
+        }
+        self.acks.insert(event.id, (event, time::now_us()));
+    }
+
+    /// In pipelined mode each reply is matched to the request of the previous transaction
+    /// instead of the one sent in the same frame, which the module has not seen yet.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+        self.in_flight = None;
+        self.resend = None;
+        self.resent = None;
+    }
+
+    /// Bus timing for this slot, slowed down while the module's replies are unreliable.
+    pub fn spi_config(&self) -> SpiConfig {
+        self.clock.config()
+    }
+
+    pub fn set_spi_config(&mut self, config: SpiConfig) {
+        self.clock.set_base(config);
+    }
+
+    /// Stops tuning the clock, for slots on a bus whose timing is not adjustable.
+    pub fn fix_clock(&mut self) {
+        self.clock.fix();
+    }
+
+    /// Returns the clock divisor now in use if it changed since the last call.
+    pub fn take_clock_change(&mut self) -> Option<u16> {
+        self.clock.take_change()
+    }
+
+    /// Queues an event the module answers with a reply carrying the same id.
+    /// The reply is available from `take_response` once it arrived.
+    pub fn request(&mut self, event: NegiconEvent) -> Result<(), DownstreamError> {
+        self.requests
+            .push(event)
+            .map_err(|_| DownstreamError::TxOverflow)
+    }
+
+    pub fn take_response(&mut self) -> Option<NegiconEvent> {
+        self.responses.pop()
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Returns true if the module has answered command `id` since its last `clear_ack`.
+    pub fn acknowledged(&self, id: u16) -> bool {
+        self.ack(id).is_some()
+    }
+
+    /// The module's answer to command `id`, if it answered since its last `clear_ack`.
+    pub fn ack(&self, id: u16) -> Option<&NegiconEvent> {
+        self.acks.get(&id).map(|(ack, _)| ack)
+    }
+
+    /// Timer value in microseconds when the answer to command `id` arrived.
+    pub fn ack_time_us(&self, id: u16) -> Option<u32> {
+        self.acks.get(&id).map(|(_, at)| *at)
+    }
+
+    /// Returns the number of invalid replies since the last call.
+    pub fn take_failed(&mut self) -> u32 {
+        core::mem::take(&mut self.failed)
+    }
+
+    pub fn clear_ack(&mut self, id: u16) {
+        self.acks.remove(&id);
+    }
+
+    /// Returns true while the controller behind this slot asked to be left alone with `BUSY`.
+    /// No transfers may be run with the slot until then.
+    pub fn is_paused(&mut self) -> bool {
+        if let Some(until) = self.paused_until_us {
+            if (until.wrapping_sub(time::now_us()) as i32) > 0 {
+                return true;
             }
-            Err(_e) => Err(DownstreamError::InvalidMessage),
+            self.paused_until_us = None;
         }
+        false
+    }
+
+    /// Returns true if outputs are waiting and the slot may be served outside the regular scan.
+    pub fn output_due(&mut self, now_us: u32) -> bool {
+        !self.is_paused()
+            && self.present
+            && (!self.replay.is_empty()
+                || !self.tx_buffer.is_empty()
+                || self.passthrough.peek().is_some())
+            && now_us.wrapping_sub(self.last_output_us) >= OUTPUT_MIN_INTERVAL_US
+    }
+
+    pub fn mark_output_served(&mut self, now_us: u32) {
+        self.last_output_us = now_us;
+    }
+
+    /// Returns true if the module has reported the control `id` or the host routed it here.
+    pub fn owns(&self, id: u16) -> bool {
+        self.ids.contains(&id) || self.mapped.contains(&id)
+    }
+
+    /// Routes host outputs for the control `id` to this slot, or stops doing so.
+    pub fn map_output(&mut self, id: u16, mapped: bool) {
+        if mapped {
+            self.mapped.insert(id);
+        } else {
+            self.mapped.remove(&id);
+        }
+    }
+
+    /// Returns true if `controller_id` is chained behind this slot.
+    pub fn reaches(&self, controller_id: u8) -> bool {
+        self.controllers.contains(&controller_id)
+    }
+
+    /// Ids seen in the events from this slot, those of chained controllers among them.
+    pub fn chained(&self) -> impl Iterator<Item = u8> + '_ {
+        self.controllers.iter().copied()
+    }
+
+    /// Stops routing events for `controller_id` here until it shows up again.
+    pub fn forget(&mut self, controller_id: u8) {
+        self.controllers.remove(&controller_id);
+        self.forwarded.remove(&controller_id);
+    }
+
+    /// Passes a host event on to a controller chained behind this slot.
+    pub fn forward(&mut self, event: NegiconEvent) -> Result<(), DownstreamError> {
+        self.forwarded.insert(event.controller_id);
+        self.passthrough
+            .push(event)
+            .map_err(|_| DownstreamError::TxOverflow)
     }
 
     pub fn send(&mut self, event: NegiconEvent) -> Result<(), DownstreamError> {
+        if matches!(event.event_type, NegiconEventType::Output) && !command::is_command(event.id) {
+            self.outputs.insert(event.id, event);
+            if !self.present {
+                // Delivered by restore_outputs once the module shows up again
+                return Ok(());
+            }
+        }
         self.tx_buffer
             .push(event)
             .map_err(|_| DownstreamError::TxOverflow)
     }
 
+    /// Longest frame the module accepts, in words including the header.
+    pub fn frame_words(&self) -> usize {
+        self.frame_words
+    }
+
+    /// Sends `payload` to the module as a single frame behind a length header and reads the
+    /// module's answer of the same length into `reply`. Returns the longest frame the module
+    /// announced in its reply header, or 0 if it did not answer with one.
+    pub fn transfer_frame(
+        &mut self,
+        interface: &mut dyn DownstreamInterface,
+        payload: &[u32],
+        reply: &mut [u32],
+    ) -> Result<usize, DownstreamError> {
+        let len = payload.len() + 1;
+        if len > self.frame_words || reply.len() != payload.len() {
+            return Err(DownstreamError::InvalidLength);
+        }
+        let mut tx = [0u32; MAX_FRAME_WORDS];
+        let mut rx = [0u32; MAX_FRAME_WORDS];
+        tx[0] = frame_header(len, MAX_FRAME_WORDS);
+        tx[1..len].copy_from_slice(payload);
+        interface.configure(self.clock.config());
+        interface.transfer_words(self.cs, &tx[..len], &mut rx[..len])?;
+        reply.copy_from_slice(&rx[1..len]);
+        Ok(parse_frame_header(rx[0]).unwrap_or(0))
+    }
+
+    /// Returns true if the module echoes a frame header on every transfer at sample `delay`.
+    /// Modules without frame support never do.
+    fn answers_at(&mut self, interface: &mut dyn DownstreamInterface, delay: u8) -> bool {
+        self.clock.set_sample_delay(delay);
+        let mut reply = [0u32; 1];
+        let mut ok = true;
+        // The first reply answers whatever was sent before
+        for round in 0..=CALIBRATION_ROUNDS {
+            let valid = matches!(
+                self.transfer_frame(interface, &[0], &mut reply),
+                Ok(announced) if announced != 0
+            );
+            if round > 0 && !valid {
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Announces our frame limit to a newly detected module and settles on the smaller of
+    /// both. The module answers one transfer late, so the header goes out twice.
+    fn negotiate_frame_len(
+        &mut self,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Result<(), DownstreamError> {
+        self.frame_words = 2;
+        let tx = [frame_header(2, MAX_FRAME_WORDS), 0];
+        let mut announced = 0;
+        for _ in 0..2 {
+            let mut rx = [0u32; 2];
+            interface.configure(self.clock.config());
+            interface.transfer_words(self.cs, &tx, &mut rx)?;
+            announced = match parse_frame_header(rx[0]) {
+                Some(words) => words,
+                None => {
+                    // A module without frame support answers with an event as usual
+                    let _ = self.handle_reply(None, &packet_of(rx[0], rx[1]));
+                    0
+                }
+            };
+        }
+        if announced > self.frame_words {
+            self.frame_words = announced.min(MAX_FRAME_WORDS);
+        }
+        Ok(())
+    }
+
+    /// Asks a newly detected module for its descriptor, to tell whether it is the one the
+    /// cached outputs belong to.
+    fn identify(&mut self) {
+        self.identifying = Some(0);
+        let request = NegiconEvent::new(
+            NegiconEventType::Output,
+            command::DESCRIPTOR,
+            u7::new(0),
+            0,
+            command::ANY_CONTROLLER,
+            0,
+        );
+        if self.request(request).is_err() {
+            self.identified(command::NO_DESCRIPTOR);
+        }
+    }
+
+    /// Replays the cached outputs if the module answered with the descriptor they were recorded
+    /// for, or if they were sent before any module was seen here. Otherwise another module took
+    /// the slot, and what was learned about the old one is dropped.
+    fn identified(&mut self, descriptor: i16) {
+        self.identifying = None;
+        let same = match self.descriptor {
+            None => true,
+            Some(known) => known == descriptor && descriptor != command::NO_DESCRIPTOR,
+        };
+        if same {
+            self.restore_outputs();
+        } else {
+            self.ids.clear();
+            self.outputs.clear();
+            self.replay.clear();
+        }
+        self.descriptor = Some(descriptor);
+    }
+
+    /// Queues the cached output state for retransmission, so a replugged module
+    /// comes back in the state the host last set.
+    fn restore_outputs(&mut self) {
+        self.replay.clear();
+        self.replay.extend(self.outputs.values().copied());
+    }
+
     pub fn receive(&mut self) -> Result<Option<NegiconEvent>, DownstreamError> {
         self.rx_buffer
             .pop()
@@ -231,7 +1212,80 @@ impl DownstreamDevice {
     }
 }
 
-fn spi_program() -> (pio::Assembler<32>, Label, Label) {
+/// Tries every MISO sample delay of a slot against the frame header its module echoes and
+/// keeps the middle of the longest range of delays that worked. One delay is tried per step,
+/// so the bus is not held for the whole calibration at once.
+pub(crate) struct Calibration {
+    slot: usize,
+    original: u8,
+    delay: u8,
+    working: u8,
+}
+
+impl Calibration {
+    pub(crate) fn start(slot: usize, ds: &DownstreamDevice) -> Self {
+        Self {
+            slot,
+            original: ds.clock.config().sample_delay,
+            delay: 0,
+            working: 0,
+        }
+    }
+
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Tries the next delay. Once all were tried, returns the chosen one, which `ds` then keeps.
+    pub(crate) fn step(
+        &mut self,
+        ds: &mut DownstreamDevice,
+        interface: &mut dyn DownstreamInterface,
+    ) -> Option<Result<u8, DownstreamError>> {
+        if ds.answers_at(interface, self.delay) {
+            self.working |= 1 << self.delay;
+        }
+        if self.delay < MAX_SAMPLE_DELAY {
+            self.delay += 1;
+            // Other slots on the bus run at their own timing in between
+            ds.clock.set_sample_delay(self.original);
+            return None;
+        }
+        Some(match middle_of_longest_run(self.working) {
+            Some(delay) => {
+                ds.clock.set_sample_delay(delay);
+                Ok(delay)
+            }
+            None => {
+                ds.clock.set_sample_delay(self.original);
+                Err(DownstreamError::InvalidMessage)
+            }
+        })
+    }
+}
+
+/// Returns the bit in the middle of the longest run of set bits in `mask`.
+fn middle_of_longest_run(mask: u8) -> Option<u8> {
+    let mut best: Option<(u8, u8)> = None;
+    let mut start = 0;
+    for bit in 0..=8u8 {
+        let set = bit < 8 && mask & (1 << bit) != 0;
+        if set {
+            continue;
+        }
+        let len = bit - start;
+        if len > 0 && best.is_none_or(|(_, best_len)| len > best_len) {
+            best = Some((start, len));
+        }
+        start = bit + 1;
+    }
+    best.map(|(start, len)| start + (len - 1) / 2)
+}
+
+/// Clocks out the words of a transfer. With `cpha` set, data is shifted out on the rising and
+/// sampled on the falling edge of SCK, otherwise the other way round. `sample_delay` stretches
+/// the clock phase before the sample edge by that many cycles.
+fn spi_program(cpha: bool, sample_delay: u8) -> (pio::Assembler<32>, Label, Label) {
     let mut program = pio::Assembler::<32>::new_with_side_set(SideSet::new(true, 1, false));
     //let mut program = pio::Assembler::<32>::new();
     let mut wrap_target = program.label();
@@ -240,21 +1294,33 @@ fn spi_program() -> (pio::Assembler<32>, Label, Label) {
     let mut next_word = program.label();
     program.bind(&mut wrap_target);
 
-    //number of words to transfer
-    program.set(pio::SetDestination::Y, 1);
+    //number of words to transfer, minus one
+    program.pull(false, true);
+    program.out(pio::OutDestination::Y, 32);
+    //wait for the cs state machine to select the module
+    program.wait(1, pio::WaitSource::IRQ, 5, false);
     program.bind(&mut next_word);
     program.pull(false, true);
     program.bind(&mut next_bit);
     // cs delay
-    program.out_with_side_set(pio::OutDestination::PINS, 1, 1);
-    program.in_with_side_set(pio::InSource::PINS, 1, 0);
+    let (shift_edge, sample_edge) = if cpha { (1, 0) } else { (0, 1) };
+    program.out_with_delay_and_side_set(
+        pio::OutDestination::PINS,
+        1,
+        sample_delay.min(MAX_SAMPLE_DELAY),
+        shift_edge,
+    );
+    program.in_with_side_set(pio::InSource::PINS, 1, sample_edge);
     program.jmp(
         pio::JmpCondition::OutputShiftRegisterNotEmpty,
         &mut next_bit,
     );
-    program.push(false, false);
+    // Leave SCK at its idle level
+    program.push_with_side_set(false, false, 0);
     program.jmp(pio::JmpCondition::YDecNonZero, &mut next_word);
     program.irq(false, false, 4, false);
+    //tell the cpu the transfer is done
+    program.irq(false, false, 0, false);
     program.bind(&mut wrap_source);
     (program, wrap_source, wrap_target)
 }
@@ -266,6 +1332,8 @@ fn cs_program() -> (pio::Assembler<32>, Label, Label) {
     program.bind(&mut wrap_target);
     program.pull(false, true);
     program.out(pio::OutDestination::PINS, 5);
+    //start the data state machine
+    program.irq(false, false, 5, false);
     //program.set(pio::SetDestination::PINS, 0x0);
     //wait for transfer to finish
     //program.nop_with_delay(31);
@@ -0,0 +1,1137 @@
+use core::{cell::Cell, future::poll_fn, task::Poll};
+
+use cortex_m::delay::Delay;
+use defmt::{debug, warn, Debug2Format};
+use embedded_hal::watchdog::Watchdog as _;
+use negicon_protocol::negicon_event::{NegiconEvent, NegiconEventType};
+use rp2040_hal::{dma::SingleChannel, rom_data::reset_to_usb_boot, watchdog::Watchdog};
+use ux::u7;
+
+use crate::{
+    attention::Attention,
+    chain::{self, Chain, HopProbe, Identity},
+    channel::Channel,
+    command::{self, Command, ResetAll, Topology},
+    dma_scan::DmaScanner,
+    firmware_update::{self, FirmwareUpdate},
+    module_update::ModuleUpdate,
+    registry::{Bus, Registry, SlotMask, MAX_SLOTS},
+    role::{self, Role},
+    scheduler::ScanScheduler,
+    spi_downstream::{
+        Calibration, DmaTargets, DownstreamDevice, DownstreamError, DownstreamInterface,
+    },
+    time::{self, Ticker},
+    upstream::{OverflowPolicy, Upstream},
+};
+
+/// Interval at which the watchdog is fed, in milliseconds.
+const WATCHDOG_FEED_MS: u32 = 500;
+/// Time in milliseconds from the start of the heartbeat to the first ping, which also confirms
+/// a freshly updated firmware as working.
+const FIRST_PING_MS: u32 = 5000;
+/// Time for the last report to reach the host before resetting into new firmware.
+const UPDATE_RESET_DELAY_MS: u32 = 100;
+
+/// How long modules get to acknowledge a reset, in milliseconds.
+const RESET_TIMEOUT_MS: u32 = 50;
+/// How long slots get to answer a descriptor request, in milliseconds.
+const DESCRIPTOR_TIMEOUT_MS: u32 = 50;
+/// How long chained controllers get to answer a probe, in milliseconds.
+const PROBE_TIMEOUT_MS: u32 = 50;
+/// How long a module update waits for the host to send more of the image, in milliseconds.
+const UPDATE_TIMEOUT_MS: u32 = 2000;
+/// Time the controllers above are asked to pause per sector written to flash, in milliseconds.
+/// Covers the typical erase time of a sector.
+const FLASH_WRITE_PAUSE_MS: u32 = 100;
+/// How long the upstream may stay disconnected before the board resets to detect its role
+/// again, in milliseconds.
+const ROLE_TIMEOUT_MS: u32 = 10_000;
+/// How long to wait for `BUSY` to leave the upstreams before writing anyway, in milliseconds.
+const BUSY_DRAIN_TIMEOUT_MS: u32 = 20;
+
+/// Events received from an upstream, tagged with the index of its link.
+pub(crate) type HostEvents = Channel<(usize, NegiconEvent), 16>;
+/// Host events for the downstream side, outputs and downstream commands.
+pub(crate) type ScanEvents = Channel<NegiconEvent, 32>;
+
+/// Connects the task serving one upstream interface to the rest of the firmware.
+pub(crate) struct UpstreamLink {
+    pub(crate) outbox: Channel<NegiconEvent, 64>,
+    policy: Cell<Option<OverflowPolicy>>,
+    /// Controls to mark as relative or absolute, see `Upstream::set_relative`.
+    relative: Channel<(u8, u16, bool), 8>,
+    connected: Cell<bool>,
+    /// Whether the upstream had sent everything it was given when it was last polled.
+    drained: Cell<bool>,
+    receive_errors: Cell<u32>,
+}
+
+impl UpstreamLink {
+    pub(crate) fn new() -> Self {
+        Self {
+            outbox: Channel::new(),
+            policy: Cell::new(None),
+            relative: Channel::new(),
+            connected: Cell::new(false),
+            drained: Cell::new(true),
+            receive_errors: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    /// Returns true once everything sent to the link has left the controller, or nobody is
+    /// listening.
+    fn is_drained(&self) -> bool {
+        !self.is_connected() || (self.outbox.is_empty() && self.drained.get())
+    }
+
+    /// Returns the number of events that could not be received since the last call.
+    fn take_receive_errors(&self) -> u32 {
+        self.receive_errors.take()
+    }
+
+    fn send(&self, event: &NegiconEvent) {
+        // Nobody would read it, and the queue would only overflow
+        if !self.is_connected() {
+            return;
+        }
+        if self.outbox.try_send(*event).is_err() {
+            warn!("Error while enqueueing event for upstream: outbox full");
+        }
+    }
+}
+
+fn broadcast(links: &[UpstreamLink], event: &NegiconEvent) {
+    for link in links {
+        link.send(event);
+    }
+}
+
+/// Asks the controllers above to leave this one alone while `sectors` flash sectors are
+/// written, and waits until the request has left. Interrupts are off during the writes, so the
+/// upstream receive FIFOs would overflow.
+async fn pause_upstreams(links: &[UpstreamLink], controller_id: u8, sectors: u32) {
+    let pause_ms = (sectors * FLASH_WRITE_PAUSE_MS).min(i16::MAX as u32) as i16;
+    broadcast(
+        links,
+        &command::report(command::BUSY, controller_id, command::ALL_SLOTS, pause_ms),
+    );
+    let started = time::now_ms();
+    let mut ticker = Ticker::every(1);
+    while !links.iter().all(UpstreamLink::is_drained)
+        && time::now_ms().wrapping_sub(started) < BUSY_DRAIN_TIMEOUT_MS
+    {
+        ticker.next().await;
+    }
+}
+
+/// Moves events between one upstream interface and the other tasks. Polls the interface every
+/// millisecond, or right away when there is something to send.
+pub(crate) async fn upstream_task(
+    index: usize,
+    mut up: Upstream<'_>,
+    link: &UpstreamLink,
+    host_events: &HostEvents,
+    identity: &Identity,
+) {
+    let mut ticker = Ticker::every(1);
+    loop {
+        poll_fn(|cx| {
+            if ticker.poll_tick(cx).is_ready() || link.outbox.poll_ready(cx).is_ready() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        if let Some(policy) = link.policy.take() {
+            debug!("Upstream overflow policy set to {:?}", policy);
+            up.set_overflow_policy(policy);
+        }
+        while let Some((controller_id, id, relative)) = link.relative.try_recv() {
+            up.set_relative(controller_id, id, relative);
+        }
+        while let Some(event) = link.outbox.try_recv() {
+            match up.send(&event) {
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Error while sending event to upstream: {:?}", e);
+                }
+            }
+        }
+        match up.poll() {
+            Ok(_) => loop {
+                match up.receive() {
+                    Ok(None) => break,
+                    Ok(Some(e)) => {
+                        debug!("Received event from upstream {:?}", Debug2Format(&e));
+                        if host_events.try_send((index, e)).is_err() {
+                            warn!("Dropping event from upstream, command queue full");
+                        }
+                    }
+                    Err(_e) => {
+                        warn!("Error while receiving event from upstream");
+                        link.receive_errors
+                            .set(link.receive_errors.get().saturating_add(1));
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("Error while polling upstream: {:?}", e);
+            }
+        }
+        link.connected.set(up.is_connected());
+        link.drained.set(up.is_drained());
+        if let Some(lost) = up.unreported_loss() {
+            warn!("Upstream {} lost {} events", index, lost);
+            let report = command::report(
+                command::LOST_EVENTS,
+                identity.get(),
+                index as u8,
+                lost.min(i16::MAX as u32) as i16,
+            );
+            up.send_report(&report);
+            up.loss_reported(lost);
+        }
+    }
+}
+
+/// Handles host events that concern the controller as a whole and passes everything else on
+/// to the scan task.
+pub(crate) async fn command_task(
+    host_events: &HostEvents,
+    links: &[UpstreamLink],
+    scan_events: &ScanEvents,
+    identity: &Identity,
+) {
+    let mut firmware: Option<FirmwareUpdate> = None;
+    loop {
+        let (index, e) = host_events.recv().await;
+        let controller_id = identity.get();
+        if e.event_type == NegiconEventType::Reboot {
+            debug!("Rebooting to USB boot");
+            reset_to_usb_boot(0, 0);
+        }
+        match Command::from_event(&e, controller_id) {
+            Some(Command::SetOverflowPolicy(policy)) => {
+                links[index].policy.set(Some(policy));
+            }
+            Some(Command::SetRelative {
+                controller_id,
+                id,
+                relative,
+            }) => {
+                let flag = (controller_id, id, relative);
+                if links[index].relative.try_send(flag).is_err() {
+                    warn!("Dropping relative flag of control {}, queue full", id);
+                }
+            }
+            Some(Command::Descriptor) => {
+                // The controller this one is chained behind is building a topology report
+                links[index].send(&command::report(
+                    command::DESCRIPTOR,
+                    controller_id,
+                    command::ALL_SLOTS,
+                    controller_id as i16,
+                ));
+            }
+            Some(Command::Probe) => {
+                let errors = links[index].take_receive_errors();
+                links[index].send(&command::report(
+                    command::PROBE,
+                    controller_id,
+                    command::ALL_SLOTS,
+                    errors.min(i16::MAX as u32) as i16,
+                ));
+            }
+            Some(Command::AssignId { nonce, id }) if identity.assign(nonce, id) => {
+                debug!("Controller id is now {}", id);
+                broadcast(
+                    links,
+                    &command::report(
+                        command::ASSIGN_ID,
+                        id,
+                        command::ALL_SLOTS,
+                        command::STATUS_OK,
+                    ),
+                );
+            }
+            Some(Command::ReleaseId) => {
+                debug!("Releasing controller id {}", controller_id);
+                identity.release();
+            }
+            Some(
+                command @ (Command::FirmwareUpdateBegin { .. }
+                | Command::FirmwareUpdateData { .. }
+                | Command::FirmwareUpdateEnd { .. }),
+            ) => {
+                // The last sector and the boot state are written at the end
+                let sectors = match (&command, firmware.as_ref()) {
+                    (Command::FirmwareUpdateData { .. }, Some(running)) => {
+                        running.fills_sector() as u32
+                    }
+                    (Command::FirmwareUpdateEnd { .. }, Some(_)) => 2,
+                    _ => 0,
+                };
+                if sectors > 0 {
+                    pause_upstreams(links, controller_id, sectors).await;
+                }
+                if handle_firmware_update(command, &mut firmware, links, controller_id) {
+                    Ticker::every(UPDATE_RESET_DELAY_MS).next().await;
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+            }
+            _ => {
+                if scan_events.try_send(e).is_err() {
+                    warn!("Dropping event for downstream, scan queue full");
+                }
+            }
+        }
+    }
+}
+
+/// Pings the upstreams and keeps the watchdog fed, which resets the controller if the executor
+/// gets stuck. Also resets it if the upstream stays disconnected for `ROLE_TIMEOUT_MS`, as
+/// the role may have been detected wrong. A root that had a host stays one, the host may only
+/// be asleep, and so does a root that found neither twice in a row, see `role::may_revisit`.
+pub(crate) async fn heartbeat_task(
+    links: &[UpstreamLink],
+    watchdog: &mut Watchdog,
+    identity: &Identity,
+    role: Role,
+) {
+    let mut ticker = Ticker::every(WATCHDOG_FEED_MS);
+    let mut ping = 0u8;
+    let mut confirmed = false;
+    let started_ms = time::now_ms();
+    let mut heard_ms = started_ms;
+    let mut hosted = false;
+    loop {
+        ticker.next().await;
+        watchdog.feed();
+        if links.iter().any(UpstreamLink::is_connected) {
+            heard_ms = time::now_ms();
+            hosted = true;
+        } else if (role != Role::Root || (!hosted && role::may_revisit(watchdog)))
+            && time::now_ms().wrapping_sub(heard_ms) >= ROLE_TIMEOUT_MS
+        {
+            warn!("Upstream disconnected for too long, detecting the role again");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        if !identity.is_assigned() {
+            broadcast(
+                links,
+                &command::report(
+                    command::ID_REQUEST,
+                    chain::UNASSIGNED,
+                    command::ALL_SLOTS,
+                    identity.nonce() as i16,
+                ),
+            );
+        }
+        // Only the first ping waits, the clock wraps around after some weeks
+        if !confirmed && time::now_ms().wrapping_sub(started_ms) < FIRST_PING_MS {
+            continue;
+        }
+        if !confirmed {
+            if firmware_update::is_unconfirmed() {
+                pause_upstreams(links, identity.get(), 1).await;
+                firmware_update::confirm_boot();
+            }
+            confirmed = true;
+        }
+        broadcast(
+            links,
+            &NegiconEvent::new(
+                NegiconEventType::Input,
+                0,
+                u7::new(0),
+                39,
+                identity.get(),
+                ping,
+            ),
+        );
+        ping = ping.wrapping_add(1);
+    }
+}
+
+/// Owns the downstream bus. Starts a DMA scan on every scan tick, processes its results once
+/// the scan completes and serves host outputs and asserted data ready lines as soon as they
+/// arrive.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn scan_task<CS: SingleChannel, TX: SingleChannel, RX: SingleChannel>(
+    registry: &mut Registry<'_>,
+    targets: DmaTargets,
+    mut scanner: DmaScanner<CS, TX, RX>,
+    mut scheduler: ScanScheduler<MAX_SLOTS>,
+    mut attention: Attention,
+    scan_events: &ScanEvents,
+    links: &[UpstreamLink],
+    delay: &mut Delay,
+    identity: &Identity,
+) {
+    let mut chain = Chain::new(identity);
+    let mut ticker = Ticker::every(scheduler.config().tick_ms);
+    let mut reset_all: Option<(ResetAll, u32)> = None;
+    let mut topology: Option<(Topology, u32)> = None;
+    let mut hop_probe: Option<(HopProbe, u32)> = None;
+    // Slots waiting for calibration, which runs for one slot at a time
+    let mut calibrate: SlotMask = 0;
+    let mut calibration: Option<Calibration> = None;
+    // Slots that asserted their data ready line and have not been read yet
+    let mut signalled: SlotMask = 0;
+    // The slots in the update and the calibration are left out of all other traffic
+    let mut update: Option<ModuleUpdate> = None;
+    let mut ticks = 0u32;
+    loop {
+        let tick = poll_fn(|cx| {
+            if let Poll::Ready(slots) = attention.poll_signalled(cx) {
+                signalled |= slots;
+            }
+            let tick = ticker.poll_tick(cx).is_ready();
+            // Host events wait for the bus while a scan is running
+            if tick
+                || signalled != 0
+                || scanner.poll_done(cx).is_ready()
+                || (!scanner.is_busy() && scan_events.poll_ready(cx).is_ready())
+            {
+                Poll::Ready(tick)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        let controller_id = chain.id();
+
+        if let Some(slots) = scanner.finish(registry.devices_mut()) {
+            for slot in slots.iter().map(|slot| *slot as usize) {
+                let ds = &mut registry.devices_mut()[slot];
+                if forward_downstream(slot, ds, links, &mut chain) {
+                    scheduler.note_activity(slot);
+                }
+            }
+        }
+
+        while !scanner.is_busy() {
+            let e = match scan_events.try_recv() {
+                Some(e) => e,
+                None => break,
+            };
+            match Command::from_event(&e, controller_id) {
+                Some(Command::ResetAll) => {
+                    debug!("Resetting all controls");
+                    let reset = ResetAll::start(registry.devices_mut(), controller_id);
+                    reset_all = Some((reset, time::now_ms()));
+                }
+                Some(Command::SetPipelined { slot, enabled }) => {
+                    debug!("Pipelined transfers for slot {}: {}", slot, enabled);
+                    for (i, ds) in registry.devices_mut().iter_mut().enumerate() {
+                        if slot == command::ALL_SLOTS || slot as usize == i {
+                            ds.set_pipelined(enabled);
+                        }
+                    }
+                }
+                Some(Command::SetSpiConfig { slot, config }) => {
+                    debug!("Bus timing for slot {}: {:?}", slot, config);
+                    for i in 0..registry.len() {
+                        if (slot == command::ALL_SLOTS || slot as usize == i)
+                            && registry.bus(i).is_some_and(Bus::is_spi)
+                        {
+                            registry.devices_mut()[i].set_spi_config(config);
+                        }
+                    }
+                }
+                Some(Command::Calibrate { slot }) => {
+                    for (i, ds) in registry.devices().iter().enumerate() {
+                        if (slot == command::ALL_SLOTS || slot as usize == i)
+                            && ds.is_present()
+                            && registry.bus(i).is_some_and(Bus::is_spi)
+                        {
+                            calibrate |= 1 << i;
+                        }
+                    }
+                }
+                Some(Command::SetAttention { slot, enabled }) => {
+                    debug!("Data ready line for slot {}: {}", slot, enabled);
+                    for i in 0..registry.len() {
+                        if slot == command::ALL_SLOTS || slot as usize == i {
+                            attention.set_enabled(i, enabled);
+                        }
+                    }
+                    scheduler.set_on_demand(attention.on_demand());
+                }
+                Some(
+                    command @ (Command::ModuleUpdateBegin { .. }
+                    | Command::ModuleUpdateData { .. }
+                    | Command::ModuleUpdateEnd { .. }),
+                ) => {
+                    handle_update(command, &mut update, registry, links, controller_id);
+                }
+                Some(Command::Topology) => {
+                    debug!("Collecting topology");
+                    topology = Some((Topology::start(registry.devices_mut()), time::now_ms()));
+                }
+                Some(Command::ChainPing) => {
+                    debug!("Probing chained controllers");
+                    hop_probe = Some((HopProbe::start(registry.devices_mut()), time::now_ms()));
+                }
+                Some(Command::MapOutput { slot, id, mapped }) => {
+                    debug!("Output {} routed to slot {}: {}", id, slot, mapped);
+                    if let Some(ds) = registry.devices_mut().get_mut(slot as usize) {
+                        ds.map_output(id, mapped);
+                    }
+                }
+                Some(Command::SetProbePeriod(ticks)) => {
+                    debug!("Empty slot probe period set to {} ticks", ticks);
+                    scheduler.set_probe_period(ticks);
+                }
+                // Not for us, so for a controller without an id further down
+                Some(Command::AssignId { .. }) => forward_chained(registry.devices_mut(), &e),
+                None => route_output(registry.devices_mut(), &e, controller_id),
+                // Taken care of by the command task
+                Some(_) => {}
+            }
+        }
+
+        let updating = update.as_ref().map(ModuleUpdate::slot);
+        let calibrating = calibration.as_ref().map(Calibration::slot);
+        let reserved = |slot| Some(slot) == updating || Some(slot) == calibrating;
+
+        // Outputs go out as soon as they are queued instead of waiting for the next scan. Only
+        // the bus of the DMA scan has to wait for it to finish.
+        let now = time::now_us();
+        for slot in 0..registry.len() {
+            if !bus_free(registry, slot, &scanner) || reserved(slot) {
+                continue;
+            }
+            let (ds, interface) = match registry.slot_mut(slot) {
+                Some(found) => found,
+                None => continue,
+            };
+            if ds.output_due(now) {
+                ds.mark_output_served(now);
+                if service_downstream(slot, ds, delay, interface, links, &mut chain).await {
+                    scheduler.note_activity(slot);
+                }
+            }
+        }
+
+        // A line still low on a tick means the module has more to report, or its edge arrived
+        // while the bus was busy
+        if tick {
+            signalled |= attention.asserted();
+        }
+        for slot in 0..registry.len() {
+            if signalled & (1 << slot) == 0 || !bus_free(registry, slot, &scanner) {
+                continue;
+            }
+            signalled &= !(1 << slot);
+            if reserved(slot) {
+                continue;
+            }
+            if let Some((ds, interface)) = registry.slot_mut(slot) {
+                if !ds.is_paused()
+                    && service_downstream(slot, ds, delay, interface, links, &mut chain).await
+                {
+                    scheduler.note_activity(slot);
+                }
+            }
+        }
+
+        if !tick {
+            continue;
+        }
+        scheduler.advance();
+        ticks = ticks.wrapping_add(1);
+        // Modules found by address come and go like the ones on empty chip selects
+        if ticks.is_multiple_of(scheduler.config().probe_period.max(1) as u32) {
+            registry.rescan();
+        }
+        if update
+            .as_ref()
+            .is_some_and(|running| running.is_abandoned(UPDATE_TIMEOUT_MS))
+        {
+            if let Some(running) = update.take() {
+                warn!("Update of slot {} abandoned by the host", running.slot());
+                abort_update(running, registry, links, controller_id);
+            }
+        }
+        if calibration.is_none() && calibrate != 0 {
+            let slot = calibrate.trailing_zeros() as usize;
+            calibrate &= !(1 << slot);
+            calibration = registry
+                .devices()
+                .get(slot)
+                .map(|ds| Calibration::start(slot, ds));
+        }
+        // One sample delay per tick, so the other slots are not held up for long
+        if let Some(running) = calibration.as_mut() {
+            let slot = running.slot();
+            let free = Some(slot) != updating && bus_free(registry, slot, &scanner);
+            let result = match registry.slot_mut(slot) {
+                Some((ds, interface)) if free => running.step(ds, interface),
+                _ => None,
+            };
+            if let Some(result) = result {
+                let delay = match result {
+                    Ok(delay) => {
+                        debug!("Slot {} samples MISO after {} cycles", slot, delay);
+                        delay as i16
+                    }
+                    Err(e) => {
+                        warn!("Calibration of slot {} failed: {:?}", slot, e);
+                        -1
+                    }
+                };
+                broadcast(
+                    links,
+                    &command::report(command::CALIBRATE, controller_id, slot as u8, delay),
+                );
+                calibration = None;
+            }
+        }
+        let calibrating = calibration.as_ref().map(Calibration::slot);
+        let reserved = |slot| Some(slot) == updating || Some(slot) == calibrating;
+        // A scan still running means the previous tick overran, so the scanned bus skips this
+        // one. The other buses are read here by the CPU, at the pace their bus allows.
+        let scan_idle = !scanner.is_busy();
+        for slot in 0..registry.len() {
+            let bus = match registry.bus(slot) {
+                Some(bus) => bus,
+                None => continue,
+            };
+            if reserved(slot) {
+                continue;
+            }
+            let (ds, interface) = match registry.slot_mut(slot) {
+                Some(found) => found,
+                None => continue,
+            };
+            // A busy chained controller would lose whatever is sent to it
+            if ds.is_paused() {
+                continue;
+            }
+            if !bus.is_scanned() {
+                if ticks.is_multiple_of(bus.poll_ticks())
+                    && service_downstream(slot, ds, delay, interface, links, &mut chain).await
+                {
+                    scheduler.note_activity(slot);
+                }
+                continue;
+            }
+            if !scan_idle || !scheduler.should_poll(slot, ds) {
+                continue;
+            }
+            if let Err(e) = ds.negotiate_if_needed(interface) {
+                warn!("Error while negotiating frame length: {:?}", e);
+            }
+            // Slots on a different bus timing than the scan are served right away
+            if !scanner.add(slot, ds)
+                && service_downstream(slot, ds, delay, interface, links, &mut chain).await
+            {
+                scheduler.note_activity(slot);
+            }
+        }
+        if scan_idle {
+            if let (Some(config), Some(interface)) =
+                (scanner.spi_config(), registry.interface(Bus::SpiA))
+            {
+                interface.configure(config);
+            }
+            scanner.start(&targets);
+        }
+        for (slot, ds) in registry.devices_mut().iter_mut().enumerate() {
+            if let Some(divisor) = ds.take_clock_change() {
+                debug!("Slot {} clock divisor now {}", slot, divisor);
+                broadcast(
+                    links,
+                    &command::report(
+                        command::CLOCK_DIVISOR,
+                        controller_id,
+                        slot as u8,
+                        divisor.min(i16::MAX as u16) as i16,
+                    ),
+                );
+            }
+        }
+        if let Some((reset, started)) = reset_all.as_mut() {
+            reset.update(registry.devices());
+            if reset.is_done() || time::now_ms().wrapping_sub(*started) >= RESET_TIMEOUT_MS {
+                let mut status = command::STATUS_OK;
+                for slot in reset.failed_slots() {
+                    warn!("Slot {} did not acknowledge reset", slot);
+                    status = command::STATUS_FAILED;
+                    broadcast(
+                        links,
+                        &command::report(
+                            command::RESET_ALL,
+                            controller_id,
+                            slot,
+                            command::STATUS_FAILED,
+                        ),
+                    );
+                }
+                broadcast(
+                    links,
+                    &command::report(
+                        command::RESET_ALL,
+                        controller_id,
+                        command::ALL_SLOTS,
+                        status,
+                    ),
+                );
+                reset_all = None;
+            }
+        }
+        if let Some(id) = chain::find_duplicate(registry.devices()) {
+            warn!("Controller id {} is in use more than once", id);
+            broadcast(
+                links,
+                &command::report(
+                    command::DUPLICATE_ID,
+                    controller_id,
+                    command::ALL_SLOTS,
+                    id as i16,
+                ),
+            );
+            let release = NegiconEvent::new(
+                NegiconEventType::Output,
+                command::RELEASE_ID,
+                u7::new(0),
+                0,
+                id,
+                0,
+            );
+            forward_chained(registry.devices_mut(), &release);
+            for ds in registry.devices_mut().iter_mut() {
+                ds.forget(id);
+            }
+        }
+        if let Some((query, started)) = topology.as_mut() {
+            query.update(registry.devices());
+            if query.is_done() || time::now_ms().wrapping_sub(*started) >= DESCRIPTOR_TIMEOUT_MS {
+                report_topology(registry.devices_mut(), links, controller_id);
+                topology = None;
+            }
+        }
+        if let Some((probe, started)) = hop_probe.as_mut() {
+            probe.update(registry.devices());
+            if probe.is_done() || time::now_ms().wrapping_sub(*started) >= PROBE_TIMEOUT_MS {
+                report_hops(probe, registry.devices_mut(), links, controller_id);
+                hop_probe = None;
+            }
+        }
+    }
+}
+
+/// Reports what each present slot holds, then passes the query on to the controllers chained
+/// behind them so they add their part of the tree.
+fn report_topology(
+    downstreams: &mut [DownstreamDevice],
+    links: &[UpstreamLink],
+    controller_id: u8,
+) {
+    let present = downstreams.iter().filter(|ds| ds.is_present()).count();
+    broadcast(
+        links,
+        &command::report(
+            command::TOPOLOGY,
+            controller_id,
+            command::ALL_SLOTS,
+            present as i16,
+        ),
+    );
+    for (slot, ds) in downstreams.iter_mut().enumerate() {
+        if !ds.is_present() {
+            continue;
+        }
+        let chained = command::chained_controller(ds);
+        let (kind, value) = match chained {
+            Some(chained) => (command::SLOT_CONTROLLER, chained as i16),
+            None => (0, command::descriptor(ds)),
+        };
+        broadcast(
+            links,
+            &NegiconEvent::new(
+                NegiconEventType::Input,
+                command::TOPOLOGY_SLOT,
+                u7::new(kind),
+                value,
+                controller_id,
+                slot as u8,
+            ),
+        );
+        if let Some(chained) = chained {
+            let query = NegiconEvent::new(
+                NegiconEventType::Output,
+                command::TOPOLOGY,
+                u7::new(0),
+                0,
+                chained,
+                0,
+            );
+            if let Err(e) = ds.forward(query) {
+                warn!(
+                    "Could not pass topology query to controller {}: {:?}",
+                    chained, e
+                );
+            }
+        }
+    }
+}
+
+/// Reports the measurements of every probed hop, then passes the chain ping on to the
+/// controllers behind them so they measure the next hops.
+fn report_hops(
+    probe: &HopProbe,
+    downstreams: &mut [DownstreamDevice],
+    links: &[UpstreamLink],
+    controller_id: u8,
+) {
+    broadcast(
+        links,
+        &command::report(
+            command::CHAIN_PING,
+            controller_id,
+            command::ALL_SLOTS,
+            probe.probed().count() as i16,
+        ),
+    );
+    for slot in probe.probed() {
+        let ds = &mut downstreams[slot];
+        let clamp = |value: u32| value.min(i16::MAX as u32) as i16;
+        let answer = probe.rtt_us(slot, ds).zip(ds.ack(command::PROBE).copied());
+        let (rtt, receive_errors) = match answer {
+            Some((rtt, answer)) => (clamp(rtt), answer.value),
+            None => (-1, -1),
+        };
+        let hop = |kind, value| command::hop_report(controller_id, slot as u8, kind, value);
+        broadcast(links, &hop(command::HOP_RTT_US, rtt));
+        broadcast(
+            links,
+            &hop(command::HOP_FAILED_TRANSFERS, clamp(ds.take_failed())),
+        );
+        broadcast(links, &hop(command::HOP_RECEIVE_ERRORS, receive_errors));
+        if let Some((_, answer)) = answer {
+            let ping = NegiconEvent::new(
+                NegiconEventType::Output,
+                command::CHAIN_PING,
+                u7::new(0),
+                0,
+                answer.controller_id,
+                0,
+            );
+            if let Err(e) = ds.forward(ping) {
+                warn!(
+                    "Could not pass chain ping to controller {}: {:?}",
+                    answer.controller_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Runs the steps of a controller firmware update and reports their outcome to the host.
+/// Returns true once the new image is staged and the controller should reset.
+fn handle_firmware_update(
+    command: Command,
+    update: &mut Option<FirmwareUpdate>,
+    links: &[UpstreamLink],
+    controller_id: u8,
+) -> bool {
+    let report = |id, status| {
+        broadcast(
+            links,
+            &command::report(id, controller_id, command::ALL_SLOTS, status),
+        )
+    };
+    match command {
+        Command::FirmwareUpdateBegin { len } => match FirmwareUpdate::start(len) {
+            Ok(started) => {
+                debug!("Receiving {} bytes of firmware", len);
+                *update = Some(started);
+                report(command::FIRMWARE_UPDATE_BEGIN, command::STATUS_OK);
+            }
+            Err(e) => {
+                warn!("Could not start firmware update: {:?}", e);
+                report(command::FIRMWARE_UPDATE_BEGIN, command::STATUS_FAILED);
+            }
+        },
+        Command::FirmwareUpdateData { seq, bytes } => {
+            let running = match update.as_mut() {
+                Some(running) => running,
+                None => return false,
+            };
+            match running.push(seq, bytes) {
+                Ok(Some(progress)) => report(command::FIRMWARE_UPDATE_DATA, progress as i16),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Firmware update aborted: {:?}", e);
+                    *update = None;
+                    report(command::FIRMWARE_UPDATE_END, command::STATUS_FAILED);
+                }
+            }
+        }
+        Command::FirmwareUpdateEnd { checksum } => {
+            let running = match update.take() {
+                Some(running) => running,
+                None => return false,
+            };
+            match running.finish(checksum) {
+                Ok(_) => {
+                    debug!("Firmware staged, resetting");
+                    report(command::FIRMWARE_UPDATE_END, command::STATUS_OK);
+                    return true;
+                }
+                Err(e) => {
+                    warn!("Firmware update failed: {:?}", e);
+                    report(command::FIRMWARE_UPDATE_END, command::STATUS_FAILED);
+                }
+            }
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Runs the steps of a module firmware update and reports their outcome to the host.
+fn handle_update(
+    command: Command,
+    update: &mut Option<ModuleUpdate>,
+    registry: &mut Registry<'_>,
+    links: &[UpstreamLink],
+    controller_id: u8,
+) {
+    match command {
+        Command::ModuleUpdateBegin { slot, len } => {
+            let status = match registry.slot_mut(slot as usize) {
+                Some((ds, interface)) if update.is_none() => {
+                    match ModuleUpdate::start(slot as usize, len, ds, interface) {
+                        Ok(started) => {
+                            *update = Some(started);
+                            command::STATUS_OK
+                        }
+                        Err(e) => {
+                            warn!("Could not start update of slot {}: {:?}", slot, e);
+                            command::STATUS_FAILED
+                        }
+                    }
+                }
+                _ => command::STATUS_FAILED,
+            };
+            broadcast(
+                links,
+                &command::report(command::MODULE_UPDATE_BEGIN, controller_id, slot, status),
+            );
+        }
+        Command::ModuleUpdateData { seq, bytes } => {
+            let running = match update.as_mut() {
+                Some(running) => running,
+                None => return,
+            };
+            let slot = running.slot();
+            let (ds, interface) = match registry.slot_mut(slot) {
+                Some(found) => found,
+                None => return,
+            };
+            match running.push(seq, bytes, ds, interface) {
+                Ok(Some(progress)) => broadcast(
+                    links,
+                    &command::report(
+                        command::MODULE_UPDATE_DATA,
+                        controller_id,
+                        slot as u8,
+                        progress as i16,
+                    ),
+                ),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Update of slot {} aborted: {:?}", slot, e);
+                    if let Some(running) = update.take() {
+                        abort_update(running, registry, links, controller_id);
+                    }
+                }
+            }
+        }
+        Command::ModuleUpdateEnd { checksum } => {
+            let mut running = match update.take() {
+                Some(running) => running,
+                None => return,
+            };
+            let slot = running.slot();
+            let (ds, interface) = match registry.slot_mut(slot) {
+                Some(found) => found,
+                None => return,
+            };
+            let status = match running.finish(checksum, ds, interface) {
+                Ok(_) => {
+                    debug!("Update of slot {} done", slot);
+                    command::STATUS_OK
+                }
+                Err(e) => {
+                    warn!("Update of slot {} failed: {:?}", slot, e);
+                    abort_update(running, registry, links, controller_id);
+                    return;
+                }
+            };
+            broadcast(
+                links,
+                &command::report(
+                    command::MODULE_UPDATE_END,
+                    controller_id,
+                    slot as u8,
+                    status,
+                ),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Takes the module of a failed or abandoned update out of its bootloader and reports the
+/// update as failed.
+fn abort_update(
+    mut running: ModuleUpdate,
+    registry: &mut Registry,
+    links: &[UpstreamLink],
+    controller_id: u8,
+) {
+    let slot = running.slot();
+    if let Some((ds, interface)) = registry.slot_mut(slot) {
+        if let Err(e) = running.abort(ds, interface) {
+            warn!(
+                "Could not take slot {} out of its bootloader: {:?}",
+                slot, e
+            );
+        }
+    }
+    broadcast(
+        links,
+        &command::report(
+            command::MODULE_UPDATE_END,
+            controller_id,
+            slot as u8,
+            command::STATUS_FAILED,
+        ),
+    );
+}
+
+/// Returns true if `slot` can be used right now, which for the scanned bus means no scan is
+/// running on it.
+fn bus_free<CS: SingleChannel, TX: SingleChannel, RX: SingleChannel>(
+    registry: &Registry,
+    slot: usize,
+    scanner: &DmaScanner<CS, TX, RX>,
+) -> bool {
+    match registry.bus(slot) {
+        Some(bus) => !bus.is_scanned() || !scanner.is_busy(),
+        None => false,
+    }
+}
+
+/// Forwards a host output to the slot whose module reported the targeted control, or that the
+/// host routed it to. Events for other controllers go to the slot they are chained behind.
+fn route_output(downstreams: &mut [DownstreamDevice], event: &NegiconEvent, controller_id: u8) {
+    if event.controller_id != controller_id {
+        forward_chained(downstreams, event);
+        return;
+    }
+    if !matches!(event.event_type, NegiconEventType::Output) {
+        return;
+    }
+    match downstreams.iter_mut().find(|ds| ds.owns(event.id)) {
+        Some(ds) => match ds.send(*event) {
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Error while enqueueing event for downstream: {:?}", e);
+            }
+        },
+        None => {
+            debug!("No module owns output {}", event.id);
+        }
+    }
+}
+
+/// Passes `event` on to every slot its controller is chained behind. That is a single one,
+/// unless the controller has no id yet or shares it with another.
+fn forward_chained(downstreams: &mut [DownstreamDevice], event: &NegiconEvent) {
+    let mut reached = false;
+    for ds in downstreams
+        .iter_mut()
+        .filter(|ds| ds.reaches(event.controller_id))
+    {
+        reached = true;
+        if let Err(e) = ds.forward(*event) {
+            warn!("Error while forwarding event to controller: {:?}", e);
+        }
+    }
+    if !reached {
+        debug!("Controller {} is not chained here", event.controller_id);
+    }
+}
+
+/// Runs one transfer with `ds` in `slot` and forwards whatever it reported to all upstreams.
+/// Returns true if the module reported input.
+async fn service_downstream(
+    slot: usize,
+    ds: &mut DownstreamDevice,
+    delay: &mut Delay,
+    interface: &mut dyn DownstreamInterface,
+    links: &[UpstreamLink],
+    chain: &mut Chain<'_>,
+) -> bool {
+    if !interface.is_ready(ds.cs()) {
+        return false;
+    }
+    match ds.poll(delay, interface).await {
+        Ok(_) => {}
+        Err(e) => match e {
+            DownstreamError::InvalidMessage => {}
+            _ => {
+                warn!("Error while polling downstream: {:?}", e);
+            }
+        },
+    }
+    forward_downstream(slot, ds, links, chain)
+}
+
+/// Forwards the events `ds` received to all upstreams. Returns true if the module reported
+/// input.
+fn forward_downstream(
+    slot: usize,
+    ds: &mut DownstreamDevice,
+    links: &[UpstreamLink],
+    chain: &mut Chain,
+) -> bool {
+    while let Some(response) = ds.take_response() {
+        debug!(
+            "Received response from downstream {:?}",
+            Debug2Format(&response)
+        );
+        if let Some(response) = chain.inbound(slot, ds, response) {
+            broadcast(links, &response);
+        }
+    }
+    // A frame brings several events at once
+    let mut input = false;
+    while let Ok(Some(e)) = ds.receive() {
+        debug!("Received event from downstream {:?}", Debug2Format(&e));
+        let e = match chain.inbound(slot, ds, e) {
+            Some(e) => e,
+            None => continue,
+        };
+        // Pings of chained controllers tell the controllers above which ids are behind
+        // this slot, those of our own modules are of no use to anyone
+        if e.is_ping() && e.controller_id == chain.id() {
+            continue;
+        }
+        broadcast(links, &e);
+        input = true;
+    }
+    input
+}
@@ -0,0 +1,113 @@
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use cortex_m::interrupt::Mutex;
+use fugit::ExtU32;
+use rp2040_hal::{
+    pac::{self, interrupt},
+    timer::{Alarm, Alarm0},
+};
+
+const TICK_US: u32 = 1000;
+const MAX_SLEEPERS: usize = 8;
+const NO_WAKER: Option<Waker> = None;
+
+/// Milliseconds since `init`, counted by the TIMER_IRQ_0 handler.
+static TICKS: AtomicU32 = AtomicU32::new(0);
+static ALARM: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
+static SLEEPERS: Mutex<RefCell<[Option<Waker>; MAX_SLEEPERS]>> =
+    Mutex::new(RefCell::new([NO_WAKER; MAX_SLEEPERS]));
+
+/// Starts the millisecond tick on hardware alarm 0.
+pub(crate) fn init(mut alarm: Alarm0) {
+    let _ = alarm.schedule(TICK_US.micros());
+    alarm.enable_interrupt();
+    cortex_m::interrupt::free(|cs| ALARM.borrow(cs).replace(Some(alarm)));
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+    }
+}
+
+pub(crate) fn now_ms() -> u32 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Microseconds from the free running timer, for measuring short intervals. Wraps after about
+/// 71 minutes.
+pub(crate) fn now_us() -> u32 {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.timerawl.read().bits()
+}
+
+fn passed(deadline: u32) -> bool {
+    (now_ms().wrapping_sub(deadline) as i32) >= 0
+}
+
+/// Wakes `waker` on the next tick.
+fn wake_on_tick(waker: &Waker) {
+    cortex_m::interrupt::free(|cs| {
+        let mut sleepers = SLEEPERS.borrow(cs).borrow_mut();
+        if let Some(slot) = sleepers
+            .iter_mut()
+            .find(|slot| slot.as_ref().is_none_or(|w| w.will_wake(waker)))
+        {
+            *slot = Some(waker.clone());
+        } else {
+            // Out of slots, try again right away instead of missing the deadline
+            waker.wake_by_ref();
+        }
+    });
+}
+
+/// Fires every `period` milliseconds. Ticks missed while the task was busy are caught up.
+pub(crate) struct Ticker {
+    period: u32,
+    next: u32,
+}
+
+impl Ticker {
+    pub(crate) fn every(period: u32) -> Self {
+        Self::starting_in(period, period)
+    }
+
+    pub(crate) fn starting_in(delay: u32, period: u32) -> Self {
+        Self {
+            period: period.max(1),
+            next: now_ms().wrapping_add(delay),
+        }
+    }
+
+    pub(crate) fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if passed(self.next) {
+            self.next = self.next.wrapping_add(self.period);
+            return Poll::Ready(());
+        }
+        wake_on_tick(cx.waker());
+        Poll::Pending
+    }
+
+    pub(crate) async fn next(&mut self) {
+        poll_fn(|cx| self.poll_tick(cx)).await
+    }
+}
+
+#[interrupt]
+fn TIMER_IRQ_0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(alarm) = ALARM.borrow(cs).borrow_mut().as_mut() {
+            alarm.clear_interrupt();
+            let _ = alarm.schedule(TICK_US.micros());
+        }
+        // The handler is the only writer, so this does not need to be atomic
+        TICKS.store(TICKS.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+        for sleeper in SLEEPERS.borrow(cs).borrow_mut().iter_mut() {
+            if let Some(waker) = sleeper.take() {
+                waker.wake();
+            }
+        }
+    });
+}
@@ -0,0 +1,109 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::convert::Infallible;
+use embedded_hal::{digital::v2::OutputPin, serial::Write};
+use rp2040_hal::uart::{Enabled, UartDevice, UartPeripheral, ValidUartPinout};
+
+use crate::{
+    spi_downstream::{DownstreamError, DownstreamInterface},
+    time,
+    uart_frame::{self, FrameReceiver},
+};
+
+/// How long the other end gets to answer a frame before the next one is sent anyway, in
+/// microseconds. It answers from its upstream task, which runs every millisecond.
+const REPLY_TIMEOUT_US: u32 = 5000;
+
+/// Downstream bus to a remote controller over a UART, the other end of `UartUpstream`. The
+/// link holds a single slot, with chip select 0.
+///
+/// Every frame is answered by the other end before the next one may be sent, which keeps a
+/// half duplex link from colliding. The answer arrives while the scan does other work, so a
+/// transfer returns the answer to the previous frame and the slot runs pipelined.
+pub(crate) struct UartDownstream<D: UartDevice, P: ValidUartPinout<D>> {
+    uart: UartPeripheral<Enabled, D, P>,
+    direction: Option<Box<dyn OutputPin<Error = Infallible>>>,
+    receiver: FrameReceiver,
+    /// Answer to the last frame, once it arrived.
+    reply: Option<[u8; 8]>,
+    /// Timer value in microseconds when the last frame went out, while its answer is due.
+    sent_us: Option<u32>,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> UartDownstream<D, P> {
+    pub(crate) fn new(
+        uart: UartPeripheral<Enabled, D, P>,
+        direction: Option<Box<dyn OutputPin<Error = Infallible>>>,
+    ) -> Self {
+        let mut downstream = Self {
+            uart,
+            direction,
+            receiver: FrameReceiver::new(),
+            reply: None,
+            sent_us: None,
+        };
+        downstream.set_direction(false);
+        downstream
+    }
+
+    fn set_direction(&mut self, sending: bool) {
+        if let Some(pin) = self.direction.as_mut() {
+            let _ = if sending {
+                pin.set_high()
+            } else {
+                pin.set_low()
+            };
+        }
+    }
+
+    /// Takes in what arrived since the last call. Frames that fail their checksum count as
+    /// not answered.
+    fn receive(&mut self) {
+        let mut bytes = [0u8; 32];
+        while let Ok(len) = self.uart.read_raw(&mut bytes) {
+            for byte in &bytes[..len] {
+                if let Some(Ok(packet)) = self.receiver.push(*byte) {
+                    self.reply = Some(packet);
+                    self.sent_us = None;
+                }
+            }
+        }
+    }
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> DownstreamInterface for UartDownstream<D, P> {
+    fn is_ready(&mut self, _cs: u8) -> bool {
+        self.receive();
+        match self.sent_us {
+            Some(sent) => time::now_us().wrapping_sub(sent) >= REPLY_TIMEOUT_US,
+            None => true,
+        }
+    }
+
+    fn transfer_words(
+        &mut self,
+        cs: u8,
+        tx: &[u32],
+        rx: &mut [u32],
+    ) -> Result<(), DownstreamError> {
+        if cs != 0 || tx.len() != 2 || rx.len() != 2 {
+            return Err(DownstreamError::InvalidLength);
+        }
+        // Only callers that did not ask `is_ready` first end up waiting here
+        while !self.is_ready(cs) {}
+        // Nothing came back reads as all ones, like an empty slot on the SPI bus
+        let reply = self.reply.take().unwrap_or([0xff; 8]);
+        let mut packet = [0u8; 8];
+        packet[..4].copy_from_slice(&tx[0].to_be_bytes());
+        packet[4..].copy_from_slice(&tx[1].to_be_bytes());
+        self.set_direction(true);
+        self.uart.write_full_blocking(&uart_frame::encode(&packet));
+        let _ = nb::block!(self.uart.flush());
+        self.set_direction(false);
+        self.sent_us = Some(time::now_us());
+        rx[0] = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+        rx[1] = u32::from_be_bytes([reply[4], reply[5], reply[6], reply[7]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use defmt::Format;
+
+use crate::checksum::Fletcher16;
+
+/// Starts every frame on a UART link.
+const SYNC: [u8; 2] = [0xa5, 0x5a];
+/// Sync word, event and Fletcher-16 checksum of the event.
+pub(crate) const FRAME_LEN: usize = 12;
+
+/// A frame whose event does not match its checksum.
+#[derive(Format)]
+pub(crate) struct ChecksumMismatch;
+
+/// Wraps an event into a frame.
+pub(crate) fn encode(packet: &[u8; 8]) -> [u8; FRAME_LEN] {
+    let mut frame = [0u8; FRAME_LEN];
+    frame[..2].copy_from_slice(&SYNC);
+    frame[2..10].copy_from_slice(packet);
+    frame[10..].copy_from_slice(&checksum(packet).to_be_bytes());
+    frame
+}
+
+fn checksum(packet: &[u8; 8]) -> u16 {
+    let mut checksum = Fletcher16::new();
+    for byte in packet {
+        checksum.add(*byte);
+    }
+    checksum.value()
+}
+
+/// Collects received bytes into frames. Bytes before a sync word are skipped, so the receiver
+/// finds the next frame after noise or a frame that was cut short.
+pub(crate) struct FrameReceiver {
+    frame: [u8; FRAME_LEN],
+    received: usize,
+}
+
+impl FrameReceiver {
+    pub(crate) fn new() -> Self {
+        Self {
+            frame: [0; FRAME_LEN],
+            received: 0,
+        }
+    }
+
+    /// Adds a received byte to the frame. Returns the event once a frame is complete, or an
+    /// error if its checksum does not match.
+    pub(crate) fn push(&mut self, byte: u8) -> Option<Result<[u8; 8], ChecksumMismatch>> {
+        if self.received < SYNC.len() {
+            if byte == SYNC[self.received] {
+                self.received += 1;
+            } else {
+                self.received = (byte == SYNC[0]) as usize;
+            }
+            return None;
+        }
+        self.frame[self.received] = byte;
+        self.received += 1;
+        if self.received < FRAME_LEN {
+            return None;
+        }
+        self.received = 0;
+        let mut packet = [0u8; 8];
+        packet.copy_from_slice(&self.frame[2..10]);
+        if checksum(&packet) != u16::from_be_bytes([self.frame[10], self.frame[11]]) {
+            self.resync();
+            return Some(Err(ChecksumMismatch));
+        }
+        Some(Ok(packet))
+    }
+
+    /// Keeps the bytes of a rejected frame from the first sync word after its start, as what
+    /// looked like a frame may have been noise with the real one starting inside it.
+    fn resync(&mut self) {
+        let start = (1..FRAME_LEN).find(|&i| {
+            self.frame[i] == SYNC[0] && (i + 1 == FRAME_LEN || self.frame[i + 1] == SYNC[1])
+        });
+        if let Some(start) = start {
+            self.frame.copy_within(start.., 0);
+            self.received = FRAME_LEN - start;
+        }
+    }
+}
@@ -1,19 +1,81 @@
-use negicon_protocol::{negicon_event::NegiconEvent, ringbuf::RingBuffer, InvalidMessage};
+extern crate alloc;
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeSet, VecDeque},
+};
+use core::convert::Infallible;
+use embedded_hal::{digital::v2::OutputPin, serial::Write};
+use negicon_protocol::{
+    make_u32,
+    negicon_event::{NegiconEvent, NegiconEventType},
+    ringbuf::RingBuffer,
+    InvalidMessage,
+};
 
 use defmt::Format;
 use frunk::{HCons, HNil};
 
-use rp2040_hal::spi::{SpiDevice, ValidSpiPinout};
-use usb_device::{class_prelude::UsbBus, device::UsbDevice, UsbError};
+use rp2040_hal::{
+    pio::{PIOExt, Rx, StateMachineIndex, Tx},
+    uart::{Enabled, UartDevice, UartPeripheral, ValidUartPinout},
+};
+use usb_device::{
+    class_prelude::UsbBus,
+    device::{UsbDevice, UsbDeviceState},
+    UsbError,
+};
 use usbd_human_interface_device::{
     interface::{InBytes8, Interface, OutBytes8, ReportSingle},
     usb_class::UsbHidClass,
 };
+
+use ux::u7;
+
+use crate::{
+    chain, command, time,
+    uart_frame::{self, FrameReceiver},
+};
+
 type HID<'a, B> =
     UsbHidClass<'a, B, HCons<Interface<'a, B, InBytes8, OutBytes8, ReportSingle>, HNil>>;
+/// What `Upstream::send` does when its queue is full.
+#[derive(Clone, Copy, Format)]
+pub(crate) enum OverflowPolicy {
+    /// Discard the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Discard the new event.
+    DropNewest,
+    /// Fold the new event into a queued event from the same control. The values of relative
+    /// controls are added up so no encoder movement is lost, absolute controls keep the newer
+    /// value. Falls back to `DropOldest` if there is none.
+    MergeDeltas,
+}
+
+impl OverflowPolicy {
+    pub(crate) fn from_value(value: i16) -> Option<Self> {
+        match value {
+            0 => Some(OverflowPolicy::DropOldest),
+            1 => Some(OverflowPolicy::DropNewest),
+            2 => Some(OverflowPolicy::MergeDeltas),
+            _ => None,
+        }
+    }
+}
+
+const PENDING_SIZE: usize = 64;
+
 pub(crate) struct Upstream<'a> {
     tx_buffer: RingBuffer<[u8; 8], 64>,
     rx_buffer: RingBuffer<[u8; 8], 64>,
+    /// Events not yet handed to the interface. Kept deserialized so the overflow policy can
+    /// inspect them.
+    pending: VecDeque<NegiconEvent>,
+    policy: OverflowPolicy,
+    /// Controls whose events carry a delta instead of a position, by controller and id.
+    relative: BTreeSet<(u8, u16)>,
+    lost: u32,
+    reported_lost: u32,
     interface: &'a mut dyn UpstreamInterface<64>,
 }
 
@@ -22,19 +84,132 @@ impl<'a> Upstream<'a> {
         Self {
             tx_buffer: RingBuffer::new(),
             rx_buffer: RingBuffer::new(),
+            pending: VecDeque::with_capacity(PENDING_SIZE),
+            policy: OverflowPolicy::DropNewest,
+            relative: BTreeSet::new(),
+            lost: 0,
+            reported_lost: 0,
             interface,
         }
     }
 
+    pub(crate) fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    pub(crate) fn set_relative(&mut self, controller_id: u8, id: u16, relative: bool) {
+        if relative {
+            self.relative.insert((controller_id, id));
+        } else {
+            self.relative.remove(&(controller_id, id));
+        }
+    }
+
+    /// Returns the number of events dropped because the queue was full, if it changed since it
+    /// was last passed to `loss_reported`. The host should resync once this changes.
+    pub(crate) fn unreported_loss(&self) -> Option<u32> {
+        (self.lost != self.reported_lost).then_some(self.lost)
+    }
+
+    /// Records that a report of `lost` dropped events was queued. Events the report itself
+    /// pushed out are counted on top and reported next time.
+    pub(crate) fn loss_reported(&mut self, lost: u32) {
+        self.reported_lost = lost;
+    }
+
     pub(crate) fn poll(&mut self) -> Result<(), UpstreamError> {
+        while let Some(event) = self.pending.front() {
+            if self.tx_buffer.push(event.serialize()).is_err() {
+                break;
+            }
+            self.pending.pop_front();
+        }
         self.interface
             .poll(&mut self.tx_buffer, &mut self.rx_buffer)
     }
 
     pub(crate) fn send(&mut self, event: &NegiconEvent) -> Result<(), UpstreamError> {
-        self.tx_buffer
-            .push(event.serialize())
-            .map_err(|_| UpstreamError::BufferOverflow)
+        if self.pending.len() < PENDING_SIZE {
+            self.pending.push_back(*event);
+            return Ok(());
+        }
+        let policy = self.policy;
+        match policy {
+            OverflowPolicy::DropNewest => {
+                self.lost = self.lost.wrapping_add(1);
+                Err(UpstreamError::BufferOverflow)
+            }
+            OverflowPolicy::MergeDeltas if self.merge(event) => Ok(()),
+            OverflowPolicy::DropOldest | OverflowPolicy::MergeDeltas => {
+                self.drop_oldest();
+                self.pending.push_back(*event);
+                Ok(())
+            }
+        }
+    }
+
+    /// Queues a report of the controller's own. Unlike other events it is never dropped, an
+    /// older report with the same id that is still queued is updated instead.
+    pub(crate) fn send_report(&mut self, report: &NegiconEvent) {
+        if let Some(queued) = self
+            .pending
+            .iter_mut()
+            .find(|queued| queued.id == report.id && queued.controller_id == report.controller_id)
+        {
+            *queued = *report;
+            return;
+        }
+        if self.pending.len() >= PENDING_SIZE {
+            self.drop_oldest();
+        }
+        self.pending.push_front(*report);
+    }
+
+    /// Drops the oldest queued event and counts it as lost. Reports are queued in front, so
+    /// that is the first event that is not one.
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .position(|queued| !command::is_command(queued.id))
+            .unwrap_or(0);
+        self.pending.remove(oldest);
+        self.lost = self.lost.wrapping_add(1);
+    }
+
+    fn merge(&mut self, event: &NegiconEvent) -> bool {
+        if !matches!(event.event_type, NegiconEventType::Input)
+            || event.is_ping()
+            || command::is_command(event.id)
+        {
+            return false;
+        }
+        let relative = self.relative.contains(&(event.controller_id, event.id));
+        match self.pending.iter_mut().rev().find(|queued| {
+            matches!(queued.event_type, NegiconEventType::Input)
+                && queued.id == event.id
+                && queued.controller_id == event.controller_id
+        }) {
+            Some(queued) if relative => {
+                queued.value = queued.value.saturating_add(event.value);
+                true
+            }
+            Some(queued) => {
+                queued.value = event.value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if something is listening on the other end of the interface.
+    pub(crate) fn is_connected(&self) -> bool {
+        self.interface.is_connected()
+    }
+
+    /// Returns true once everything queued was handed to the other end.
+    pub(crate) fn is_drained(&mut self) -> bool {
+        self.pending.is_empty() && self.tx_buffer.peek().is_none() && self.interface.is_drained()
     }
 
     pub(crate) fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
@@ -103,6 +278,197 @@ where
         }
         Ok(())
     }
+
+    fn is_connected(&self) -> bool {
+        self.dev.state() == UsbDeviceState::Configured
+    }
+}
+
+/// How long a controller link counts as connected after the other end was last heard, in
+/// milliseconds. The upper controller polls every slot well within that.
+const PEER_TIMEOUT_MS: u32 = 1000;
+
+fn heard_recently(heard_ms: Option<u32>) -> bool {
+    heard_ms.is_some_and(|at| time::now_ms().wrapping_sub(at) < PEER_TIMEOUT_MS)
+}
+
+/// Upstream link to another controller, which clocks this board as an SPI slave through the
+/// PIO slave state machine. Every transfer carries one event in each direction.
+pub(crate) struct PioSpiUpstream<P: PIOExt, SM: StateMachineIndex> {
+    tx: Tx<(P, SM)>,
+    rx: Rx<(P, SM)>,
+    /// First word of an event whose second word has not arrived yet.
+    partial: Option<u32>,
+    /// When the master last clocked an event in, in milliseconds.
+    heard_ms: Option<u32>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> PioSpiUpstream<P, SM> {
+    pub(crate) fn new(tx: Tx<(P, SM)>, rx: Rx<(P, SM)>) -> Self {
+        Self {
+            tx,
+            rx,
+            partial: None,
+            heard_ms: None,
+        }
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, const SIZE: usize> UpstreamInterface<SIZE>
+    for PioSpiUpstream<P, SM>
+{
+    fn poll(
+        &mut self,
+        tx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
+        rx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
+    ) -> Result<(), UpstreamError> {
+        while let Some(word) = self.rx.read() {
+            self.heard_ms = Some(time::now_ms());
+            match self.partial.take() {
+                None => self.partial = Some(word),
+                Some(first) => {
+                    let mut packet = [0u8; 8];
+                    packet[..4].copy_from_slice(&first.to_be_bytes());
+                    packet[4..].copy_from_slice(&word.to_be_bytes());
+                    if rx_buffer.push(packet).is_err() {
+                        return Err(UpstreamError::BufferOverflow);
+                    }
+                }
+            }
+        }
+        // Only queue a whole event, the master decides when it is clocked out
+        if self.tx.is_empty() {
+            if let Some(packet) = tx_buffer.pop() {
+                self.tx
+                    .write(make_u32(packet[0], packet[1], packet[2], packet[3]));
+                self.tx
+                    .write(make_u32(packet[4], packet[5], packet[6], packet[7]));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        heard_recently(self.heard_ms)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.tx.is_empty()
+    }
+}
+
+/// Frames sent per poll in full duplex mode, which keeps waiting for the FIFO short.
+const UART_FRAMES_PER_POLL: usize = 4;
+
+/// Upstream link over a UART, for boards too far away for SPI. Every event is sent in its own
+/// frame with a checksum, frames that fail it are dropped.
+///
+/// With an RS-485 transceiver the link is half duplex. Its direction pin is driven high while
+/// sending, and like on the SPI link an event is only sent in answer to one from the other
+/// end, the `UartDownstream` of the controller above. Every frame from there is answered, with
+/// an idle event if there is nothing to send.
+pub(crate) struct UartUpstream<D: UartDevice, P: ValidUartPinout<D>> {
+    uart: UartPeripheral<Enabled, D, P>,
+    direction: Option<Box<dyn OutputPin<Error = Infallible>>>,
+    receiver: FrameReceiver,
+    /// Frames from the other end not answered yet, in half duplex mode.
+    turns: usize,
+    /// When the last valid frame arrived, in milliseconds.
+    heard_ms: Option<u32>,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> UartUpstream<D, P> {
+    pub(crate) fn new(
+        uart: UartPeripheral<Enabled, D, P>,
+        direction: Option<Box<dyn OutputPin<Error = Infallible>>>,
+    ) -> Self {
+        let mut upstream = Self {
+            uart,
+            direction,
+            receiver: FrameReceiver::new(),
+            turns: 0,
+            heard_ms: None,
+        };
+        upstream.set_direction(false);
+        upstream
+    }
+
+    fn set_direction(&mut self, sending: bool) {
+        if let Some(pin) = self.direction.as_mut() {
+            let _ = if sending { pin.set_high() } else { pin.set_low() };
+        }
+    }
+}
+
+/// A ping as modules send it, which the other end drops.
+fn idle_packet() -> [u8; 8] {
+    NegiconEvent::new(
+        NegiconEventType::Input,
+        0,
+        u7::new(0),
+        0,
+        chain::MODULE_ID,
+        0,
+    )
+    .serialize()
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>, const SIZE: usize> UpstreamInterface<SIZE>
+    for UartUpstream<D, P>
+{
+    fn poll(
+        &mut self,
+        tx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
+        rx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
+    ) -> Result<(), UpstreamError> {
+        let mut result = Ok(());
+        let mut bytes = [0u8; 32];
+        while let Ok(len) = self.uart.read_raw(&mut bytes) {
+            for byte in &bytes[..len] {
+                match self.receiver.push(*byte) {
+                    Some(Ok(packet)) => {
+                        self.heard_ms = Some(time::now_ms());
+                        self.turns += 1;
+                        if rx_buffer.push(packet).is_err() {
+                            result = Err(UpstreamError::BufferOverflow);
+                        }
+                    }
+                    Some(Err(_)) => result = Err(UpstreamError::Checksum),
+                    None => {}
+                }
+            }
+        }
+        let half_duplex = self.direction.is_some();
+        let mut count = if half_duplex {
+            core::mem::take(&mut self.turns)
+        } else {
+            UART_FRAMES_PER_POLL
+        };
+        while count > 0 {
+            let packet = match tx_buffer.pop() {
+                Some(packet) => packet,
+                // Every turn is answered, so the other end never has to wait for a timeout
+                None if half_duplex => idle_packet(),
+                None => break,
+            };
+            if half_duplex {
+                self.set_direction(true);
+            }
+            self.uart.write_full_blocking(&uart_frame::encode(&packet));
+            count -= 1;
+        }
+        if half_duplex {
+            // The transceiver has to keep driving the bus until the last stop bit is out, and
+            // let go of it right after, as the other end talks again as soon as it has the reply
+            let _ = nb::block!(self.uart.flush());
+            self.set_direction(false);
+        }
+        result
+    }
+
+    fn is_connected(&self) -> bool {
+        heard_recently(self.heard_ms)
+    }
 }
 
 pub(crate) trait UpstreamInterface<const SIZE: usize> {
@@ -111,6 +477,13 @@ pub(crate) trait UpstreamInterface<const SIZE: usize> {
         tx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
         rx_buffer: &mut RingBuffer<[u8; 8], SIZE>,
     ) -> Result<(), UpstreamError>;
+
+    fn is_connected(&self) -> bool;
+
+    /// Returns true once the interface has sent everything it took from the tx buffer.
+    fn is_drained(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Format)]
@@ -119,5 +492,7 @@ pub(crate) enum UpstreamError {
     UsbError(UsbError),
     BufferOverflow,
     InvalidMessage(InvalidMessage),
+    /// A frame on the UART failed its checksum.
+    Checksum,
     Generic,
 }